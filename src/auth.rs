@@ -0,0 +1,1088 @@
+use crate::{
+    jwt::{JwtVerifier, TokenClaims},
+    keys::KeyStoreData,
+    request_body_limit, AppState, EmailError, FieldError, ServerConfig,
+};
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use lettre::Address;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+// 兼容旧配置的 api_key 使用的客户端名称
+const LEGACY_CLIENT: &str = "default";
+
+// JWT 客户端名称的前缀，与 API key 的客户端分开，不能冒用其邮件记录、幂等键和频率限制
+const JWT_CLIENT_PREFIX: &str = "jwt:";
+
+// hash-key 生成的 key 的前缀
+const GENERATED_KEY_PREFIX: &str = "esk_";
+
+// 签名请求使用的请求头
+const KEY_ID_HEADER: &str = "X-Key-Id";
+const TIMESTAMP_HEADER: &str = "X-Timestamp";
+const NONCE_HEADER: &str = "X-Nonce";
+const SIGNATURE_HEADER: &str = "X-Signature";
+
+// nonce 的最大长度
+const MAX_NONCE_LENGTH: usize = 128;
+
+// 客户端的认证方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    #[default]
+    ApiKey, // 在 X-API-Key 头中发送 key
+    Hmac, // 使用 hmac_secret 对请求签名
+}
+
+// API key 的权限
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    Send,       // /send-email 和 /send-email/multipart，以及取消邮件
+    Batch,      // /send-batch
+    Templates,  // 管理和渲染模板
+    ReadStatus, // 查询邮件状态
+    Admin,      // 所有权限，并且可以查看其他客户端的邮件
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Scope::Send => "send",
+            Scope::Batch => "batch",
+            Scope::Templates => "templates",
+            Scope::ReadStatus => "read-status",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "send" => Ok(Scope::Send),
+            "batch" => Ok(Scope::Batch),
+            "templates" => Ok(Scope::Templates),
+            "read-status" => Ok(Scope::ReadStatus),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// API key 配置
+#[derive(Debug, Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    pub name: String, // 客户端名称，出现在日志和邮件记录中
+    #[serde(default)] // 认证方式，默认使用 X-API-Key
+    pub auth: AuthMethod,
+    #[serde(default)] // 明文 key，与 key_hash 二选一
+    pub key: Option<String>,
+    #[serde(default)] // 加盐哈希后的 key，由 hash-key 子命令生成
+    pub key_hash: Option<String>,
+    #[serde(default)] // key 的开头部分，用于快速找到对应的哈希
+    pub key_prefix: Option<String>,
+    #[serde(default)] // HMAC 签名密钥，auth 为 hmac 时必填
+    pub hmac_secret: Option<String>,
+    #[serde(default)] // 过期时间，未配置时不过期
+    pub expires_at: Option<DateTime<Utc>>,
+    pub scopes: Vec<Scope>,
+    #[serde(default)] // 可以使用的 SMTP 账户，未配置时可以使用所有账户
+    pub accounts: Option<Vec<String>>,
+    #[serde(default)] // 允许的发件人地址或域名，未配置时不限制
+    pub allowed_from: Option<Vec<String>>,
+    #[serde(default)] // 允许的收件人域名，未配置时不限制
+    pub allowed_recipient_domains: Option<Vec<String>>,
+    #[serde(default = "default_rate_limit")] // 每分钟最多请求数
+    pub rate_limit: u32,
+}
+
+// 默认频率限制：每分钟 10 次
+pub fn default_rate_limit() -> u32 {
+    10
+}
+
+// 通过认证的客户端及其权限
+#[derive(Debug)]
+pub struct ApiClient {
+    pub name: String,
+    scopes: HashSet<Scope>,
+    accounts: Option<Vec<String>>,
+    allowed_from: Option<Vec<String>>,
+    allowed_recipient_domains: Option<Vec<String>>,
+    rate_limit: u32,
+}
+
+impl ApiClient {
+    fn new(config: &ApiKeyConfig) -> Self {
+        ApiClient {
+            name: config.name.clone(),
+            scopes: config.scopes.iter().copied().collect(),
+            accounts: config.accounts.clone(),
+            allowed_from: lowercase(&config.allowed_from),
+            allowed_recipient_domains: lowercase(&config.allowed_recipient_domains),
+            rate_limit: config.rate_limit,
+        }
+    }
+
+    // 由 JWT 声明生成客户端，名称为 jwt:<sub>，忽略不认识的 scope
+    fn from_claims(claims: TokenClaims, rate_limit: u32) -> Self {
+        let scopes = claims
+            .scope
+            .as_ref()
+            .map(|scope| {
+                scope
+                    .names()
+                    .into_iter()
+                    .filter_map(|name| name.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        ApiClient {
+            scopes,
+            allowed_from: lowercase(&claims.allowed_from),
+            allowed_recipient_domains: lowercase(&claims.allowed_recipient_domains),
+            name: format!("{}{}", JWT_CLIENT_PREFIX, claims.sub),
+            accounts: claims.accounts,
+            rate_limit,
+        }
+    }
+
+    // admin 拥有所有权限
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.scopes.contains(&Scope::Admin)
+    }
+
+    pub fn require(&self, scope: Scope) -> Result<(), EmailError> {
+        if !self.has_scope(scope) {
+            warn!("Client {} lacks scope {}", self.name, scope);
+            return Err(EmailError::ScopeNotAllowed(scope));
+        }
+        Ok(())
+    }
+
+    pub fn check_account(&self, account: &str) -> Result<(), EmailError> {
+        if let Some(accounts) = &self.accounts {
+            if !accounts.iter().any(|allowed| allowed == account) {
+                warn!(
+                    "Client {} is not allowed to use account {}",
+                    self.name, account
+                );
+                return Err(EmailError::AccountNotAllowed(account.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // 检查发件人和收件人是否在允许范围内，返回所有不允许的地址
+    pub fn check_addresses<'a>(
+        &self,
+        from: (&str, &Address),
+        recipients: impl IntoIterator<Item = (String, &'a Address)>,
+    ) -> Result<(), EmailError> {
+        let mut errors = Vec::new();
+        if let Some(allowed) = &self.allowed_from {
+            let (field, address) = from;
+            let email = address.to_string().to_ascii_lowercase();
+            if !allowed
+                .iter()
+                .any(|allowed| *allowed == email || domain_matches(address, allowed))
+            {
+                errors.push(FieldError {
+                    field: field.to_string(),
+                    reason: format!("sender {} is not allowed for this API key", address),
+                });
+            }
+        }
+        if let Some(allowed) = &self.allowed_recipient_domains {
+            for (field, address) in recipients {
+                if !allowed
+                    .iter()
+                    .any(|allowed| domain_matches(address, allowed))
+                {
+                    errors.push(FieldError {
+                        field,
+                        reason: format!("recipient {} is not allowed for this API key", address),
+                    });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        warn!(
+            "Client {} used {} address(es) outside its policy",
+            self.name,
+            errors.len()
+        );
+        Err(EmailError::AddressNotAllowed(errors))
+    }
+}
+
+// 地址和域名不区分大小写，域名可以写成 @example.com
+fn lowercase(list: &Option<Vec<String>>) -> Option<Vec<String>> {
+    list.as_ref().map(|list| {
+        list.iter()
+            .map(|item| item.trim_start_matches('@').to_ascii_lowercase())
+            .collect()
+    })
+}
+
+// 地址的域名为该域名或其子域名
+fn domain_matches(address: &Address, domain: &str) -> bool {
+    let address_domain = address.domain().to_ascii_lowercase();
+    address_domain == domain
+        || address_domain
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+// 保存的 key，明文 key 也只保留哈希，比较时不会泄露长度
+enum StoredKey {
+    Plain(String),
+    Hashed { salt: String, hash: String },
+}
+
+impl StoredKey {
+    fn parse(config: &ApiKeyConfig) -> Result<Self, String> {
+        match (&config.key, &config.key_hash) {
+            (Some(key), None) if !key.is_empty() => Ok(StoredKey::Plain(hash_key("", key))),
+            (None, Some(key_hash)) => StoredKey::parse_hash(&config.name, key_hash),
+            _ => Err(format!(
+                "API key {} must have either key or key_hash",
+                config.name
+            )),
+        }
+    }
+
+    // key_hash 的格式为 sha256:<salt>:<hash>
+    fn parse_hash(name: &str, key_hash: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid key_hash of API key {}", name);
+        let mut parts = key_hash.splitn(3, ':');
+        let (Some("sha256"), Some(salt), Some(hash)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        Ok(StoredKey::Hashed {
+            salt: salt.to_string(),
+            hash: hash.to_ascii_lowercase(),
+        })
+    }
+
+    // 常量时间比较
+    fn matches(&self, key: &str) -> bool {
+        let (salt, hash) = match self {
+            StoredKey::Plain(hash) => ("", hash),
+            StoredKey::Hashed { salt, hash } => (salt.as_str(), hash),
+        };
+        hash_key(salt, key).as_bytes().ct_eq(hash.as_bytes()).into()
+    }
+}
+
+fn hash_key(salt: &str, key: &str) -> String {
+    format!(
+        "{:x}",
+        Sha256::new()
+            .chain_update(salt)
+            .chain_update(key)
+            .finalize()
+    )
+}
+
+// 生成随机 key，返回 key 和它的前缀
+pub fn generate_key() -> (String, String) {
+    let prefix = format!(
+        "{}{}_",
+        GENERATED_KEY_PREFIX,
+        &Uuid::new_v4().simple().to_string()[..8]
+    );
+    let key = format!("{}{}", prefix, Uuid::new_v4().simple());
+    (key, prefix)
+}
+
+// 使用随机盐计算 key_hash
+pub fn key_hash(key: &str) -> String {
+    let salt = Uuid::new_v4().simple().to_string();
+    format!("sha256:{}:{}", salt, hash_key(&salt, key))
+}
+
+// 客户端的凭据
+enum Credential {
+    Key {
+        prefix: Option<String>,
+        stored: StoredKey,
+    },
+    Hmac {
+        secret: String,
+    },
+}
+
+impl Credential {
+    fn parse(config: &ApiKeyConfig) -> Result<Self, String> {
+        match config.auth {
+            AuthMethod::ApiKey => Ok(Credential::Key {
+                prefix: config
+                    .key_prefix
+                    .clone()
+                    .filter(|prefix| !prefix.is_empty()),
+                stored: StoredKey::parse(config)?,
+            }),
+            AuthMethod::Hmac => match &config.hmac_secret {
+                Some(secret) if !secret.is_empty() => Ok(Credential::Hmac {
+                    secret: secret.clone(),
+                }),
+                _ => Err(format!(
+                    "API key {} uses hmac auth but has no hmac_secret",
+                    config.name
+                )),
+            },
+        }
+    }
+}
+
+struct ApiKeyEntry {
+    credential: Credential,
+    expires_at: Option<DateTime<Utc>>,
+    disabled: bool,
+    client: Arc<ApiClient>,
+}
+
+impl ApiKeyEntry {
+    fn check_usable(&self) -> Result<(), EmailError> {
+        if self.disabled {
+            warn!("Disabled API key of client {} provided", self.client.name);
+            return Err(EmailError::ApiKeyDisabled);
+        }
+        if self
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            warn!("Expired credential of client {} provided", self.client.name);
+            return Err(EmailError::ApiKeyExpired);
+        }
+        Ok(())
+    }
+}
+
+// 所有 API key，负责认证和按客户端限制频率
+pub struct ApiKeys {
+    configs: Vec<ApiKeyConfig>,
+    keys: Vec<ApiKeyEntry>,
+    managed: RwLock<Vec<ApiKeyEntry>>, // 通过管理接口创建的 key
+    disabled_configured: RwLock<HashSet<String>>, // 通过管理接口停用的 app_config.json 中的 key
+    rate_limit: Mutex<RateLimit>,
+    signature_max_skew: Duration,
+    nonces: Mutex<HashMap<(String, String), Instant>>, // 已使用的 nonce 及其使用时间
+    jwt: Option<JwtVerifier>,
+}
+
+impl ApiKeys {
+    // 合并 api_keys、旧配置中的 api_key 和运行时创建的 key，名称或 key 重复时返回错误
+    pub fn new(config: &ServerConfig, store: &KeyStoreData) -> Result<Self, String> {
+        let mut configs = config.api_keys.clone();
+        if let Some(key) = &config.api_key {
+            configs.push(ApiKeyConfig {
+                name: LEGACY_CLIENT.to_string(),
+                auth: AuthMethod::ApiKey,
+                key: Some(key.clone()),
+                key_hash: None,
+                key_prefix: None,
+                hmac_secret: None,
+                expires_at: None,
+                scopes: vec![Scope::Admin],
+                accounts: config.allowed_accounts.clone(),
+                allowed_from: None,
+                allowed_recipient_domains: None,
+                rate_limit: default_rate_limit(),
+            });
+        }
+        let jwt = config.jwt.as_ref().map(JwtVerifier::new).transpose()?;
+        if configs.is_empty() && store.keys.is_empty() && jwt.is_none() {
+            return Err("no API key configured, set api_key, api_keys or jwt".to_string());
+        }
+
+        let mut names = HashSet::new();
+        let mut plain_keys = HashSet::new();
+        let mut keys = Vec::with_capacity(configs.len());
+        for config in &configs {
+            if config.name.is_empty() {
+                return Err("API keys must have a name".to_string());
+            }
+            if config.name.starts_with(JWT_CLIENT_PREFIX) {
+                return Err(format!(
+                    "API key name {} must not start with {}",
+                    config.name, JWT_CLIENT_PREFIX
+                ));
+            }
+            if !names.insert(config.name.as_str()) {
+                return Err(format!("duplicate API key name: {}", config.name));
+            }
+            let credential = Credential::parse(config)?;
+            if let Credential::Key {
+                stored: StoredKey::Plain(hash),
+                ..
+            } = &credential
+            {
+                if !plain_keys.insert(hash.clone()) {
+                    return Err(format!("API key of {} is used more than once", config.name));
+                }
+            }
+            if config
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= Utc::now())
+            {
+                warn!("API key {} has already expired", config.name);
+            }
+            keys.push(ApiKeyEntry {
+                credential,
+                expires_at: config.expires_at,
+                disabled: false,
+                client: Arc::new(ApiClient::new(config)),
+            });
+        }
+        if let Some(key) = store
+            .keys
+            .iter()
+            .find(|key| names.contains(key.name.as_str()))
+        {
+            return Err(format!(
+                "API key {} in the key store is also defined in app_config.json",
+                key.name
+            ));
+        }
+        info!("Loaded {} API key(s)", keys.len());
+
+        let api_keys = ApiKeys {
+            configs,
+            keys,
+            managed: RwLock::new(Vec::new()),
+            disabled_configured: RwLock::new(HashSet::new()),
+            rate_limit: Mutex::new(RateLimit::new()),
+            signature_max_skew: Duration::from_secs(config.signature_max_skew),
+            nonces: Mutex::new(HashMap::new()),
+            jwt,
+        };
+        api_keys.set_managed(store)?;
+        Ok(api_keys)
+    }
+
+    // app_config.json 中配置的 key，不能在运行时修改
+    pub fn configured(&self) -> &[ApiKeyConfig] {
+        &self.configs
+    }
+
+    pub fn is_configured(&self, name: &str) -> bool {
+        self.configs.iter().any(|config| config.name == name)
+    }
+
+    // 替换运行时创建的 key 和停用状态，轮换期间旧 key 作为单独的条目，在过渡期结束前仍然有效
+    pub fn set_managed(&self, store: &KeyStoreData) -> Result<(), String> {
+        let now = Utc::now();
+        let mut entries = Vec::with_capacity(store.keys.len());
+        for key in &store.keys {
+            let config = key.config();
+            let client = Arc::new(ApiClient::new(&config));
+            entries.push(ApiKeyEntry {
+                credential: Credential::parse(&config)?,
+                expires_at: config.expires_at,
+                disabled: key.disabled,
+                client: client.clone(),
+            });
+            if let Some(previous) = key
+                .previous_key
+                .as_ref()
+                .filter(|previous| previous.expires_at > now)
+            {
+                entries.push(ApiKeyEntry {
+                    credential: Credential::Key {
+                        prefix: Some(previous.key_prefix.clone()),
+                        stored: StoredKey::parse_hash(&key.name, &previous.key_hash)?,
+                    },
+                    expires_at: Some(config.expires_at.map_or(previous.expires_at, |expires_at| {
+                        expires_at.min(previous.expires_at)
+                    })),
+                    disabled: key.disabled,
+                    client,
+                });
+            }
+        }
+        for name in &store.disabled_config_keys {
+            if !self.is_configured(name) {
+                warn!("Disabled API key {} is no longer in app_config.json", name);
+            }
+        }
+        debug!("Loaded {} runtime API key(s)", store.keys.len());
+        *self.managed.write().unwrap() = entries;
+        *self.disabled_configured.write().unwrap() =
+            store.disabled_config_keys.iter().cloned().collect();
+        Ok(())
+    }
+
+    // 根据 X-API-Key 头识别客户端
+    fn authenticate_key(&self, headers: &HeaderMap) -> Result<Arc<ApiClient>, EmailError> {
+        debug!("Checking for API key in headers...");
+        let request_api_key = headers
+            .get("X-API-Key")
+            .ok_or_else(|| {
+                warn!("No API key provided in request");
+                EmailError::MissingApiKey
+            })?
+            .to_str()
+            .map_err(|e| {
+                warn!("Invalid API key format: {}", e);
+                EmailError::InvalidApiKey
+            })?;
+
+        // 比较所有前缀匹配的 key，不在找到后提前结束
+        let managed = self.managed.read().unwrap();
+        let mut found = None;
+        for entry in self.keys.iter().chain(managed.iter()) {
+            let Credential::Key { prefix, stored } = &entry.credential else {
+                continue;
+            };
+            if prefix
+                .as_ref()
+                .is_some_and(|prefix| !request_api_key.starts_with(prefix.as_str()))
+            {
+                continue;
+            }
+            if stored.matches(request_api_key) {
+                found = Some(entry);
+            }
+        }
+        let entry = found.ok_or_else(|| {
+            warn!("Invalid API key provided");
+            EmailError::InvalidApiKey
+        })?;
+
+        self.check_usable(entry)?;
+        debug!("Authenticated client {}", entry.client.name);
+        Ok(entry.client.clone())
+    }
+
+    // 运行时创建的 key 与 app_config.json 中的 key 不会重名，按名称检查停用状态即可
+    fn check_usable(&self, entry: &ApiKeyEntry) -> Result<(), EmailError> {
+        if self
+            .disabled_configured
+            .read()
+            .unwrap()
+            .contains(&entry.client.name)
+        {
+            warn!("Disabled API key of client {} provided", entry.client.name);
+            return Err(EmailError::ApiKeyDisabled);
+        }
+        entry.check_usable()
+    }
+
+    // 校验 Authorization: Bearer 头中的 JWT
+    fn authenticate_token(&self, token: &str) -> Result<Arc<ApiClient>, EmailError> {
+        let jwt = self.jwt.as_ref().ok_or_else(|| {
+            warn!("Bearer token provided but jwt is not configured");
+            EmailError::InvalidToken("bearer tokens are not accepted".to_string())
+        })?;
+        let client = ApiClient::from_claims(jwt.verify(token)?, jwt.rate_limit);
+        debug!("Authenticated client {} with a bearer token", client.name);
+        Ok(Arc::new(client))
+    }
+
+    // 校验签名请求：签名内容为 方法、路径（含查询参数）、时间戳、nonce 各占一行，后接请求体
+    fn authenticate_signature(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> Result<Arc<ApiClient>, EmailError> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| {
+                    warn!("Signed request without a valid {} header", name);
+                    EmailError::InvalidSignature(format!("missing or invalid {} header", name))
+                })
+        };
+        let key_id = header(KEY_ID_HEADER)?;
+        let timestamp = header(TIMESTAMP_HEADER)?;
+        let nonce = header(NONCE_HEADER)?;
+        let signature = header(SIGNATURE_HEADER)?;
+
+        let entry = self
+            .keys
+            .iter()
+            .find(|entry| entry.client.name == key_id)
+            .ok_or_else(|| {
+                warn!("Signed request from unknown client {}", key_id);
+                EmailError::InvalidSignature("unknown key id".to_string())
+            })?;
+        let Credential::Hmac { secret } = &entry.credential else {
+            warn!("Client {} does not use request signing", key_id);
+            return Err(EmailError::InvalidSignature(
+                "key id does not use request signing".to_string(),
+            ));
+        };
+
+        // 时间戳超出允许偏差的请求直接拒绝，nonce 只需保留这段时间
+        let sent_at = timestamp.parse::<i64>().map_err(|_| {
+            EmailError::InvalidSignature(format!("{} must be unix seconds", TIMESTAMP_HEADER))
+        })?;
+        if Utc::now().timestamp().abs_diff(sent_at) > self.signature_max_skew.as_secs() {
+            warn!(
+                "Signed request from client {} has a stale timestamp {}",
+                key_id, timestamp
+            );
+            return Err(EmailError::InvalidSignature(
+                "timestamp is outside the allowed window".to_string(),
+            ));
+        }
+        if nonce.len() > MAX_NONCE_LENGTH {
+            return Err(EmailError::InvalidSignature(format!(
+                "{} must be at most {} characters",
+                NONCE_HEADER, MAX_NONCE_LENGTH
+            )));
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes());
+        mac.update(body);
+        let expected = format!("{:x}", mac.finalize().into_bytes());
+        if !bool::from(
+            expected
+                .as_bytes()
+                .ct_eq(signature.to_ascii_lowercase().as_bytes()),
+        ) {
+            warn!("Invalid request signature from client {}", key_id);
+            return Err(EmailError::InvalidSignature(
+                "signature does not match".to_string(),
+            ));
+        }
+        self.check_usable(entry)?;
+
+        // 签名正确后再记录 nonce，避免伪造请求占用 nonce
+        let now = Instant::now();
+        let mut nonces = self.nonces.lock().unwrap();
+        let window = self.signature_max_skew * 2;
+        nonces.retain(|_, used_at| now.duration_since(*used_at) < window);
+        if nonces
+            .insert((key_id.to_string(), nonce.to_string()), now)
+            .is_some()
+        {
+            warn!("Replayed nonce {} from client {}", nonce, key_id);
+            return Err(EmailError::InvalidSignature(
+                "nonce has already been used".to_string(),
+            ));
+        }
+        debug!("Authenticated signed request from client {}", key_id);
+        Ok(entry.client.clone())
+    }
+
+    // 按客户端检查频率限制
+    pub fn check_rate_limit(&self, client: &ApiClient) -> Result<(), EmailError> {
+        let mut rate_limit = self.rate_limit.lock().unwrap();
+        if !rate_limit.is_allowed(&client.name, client.rate_limit) {
+            return Err(EmailError::RateLimit);
+        }
+        Ok(())
+    }
+}
+
+// 认证中间件：带 Bearer 令牌的请求校验 JWT，带 X-Signature 的请求校验签名，
+// 其他请求校验 X-API-Key，通过后把客户端放入请求扩展中
+pub async fn authenticate(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, EmailError> {
+    let (mut parts, body) = request.into_parts();
+    let bearer = parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let (client, body) = if let Some(token) = bearer {
+        (state.api_keys.authenticate_token(token.trim())?, body)
+    } else if parts.headers.contains_key(SIGNATURE_HEADER) {
+        // 签名覆盖请求体，需要先读出完整的请求体
+        let body = axum::body::to_bytes(body, request_body_limit(&state.app_config.server))
+            .await
+            .map_err(|e| {
+                warn!("Failed to read request body: {}", e);
+                EmailError::InvalidRequest(format!("Failed to read request body: {}", e))
+            })?;
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str());
+        let client = state.api_keys.authenticate_signature(
+            parts.method.as_str(),
+            path,
+            &parts.headers,
+            &body,
+        )?;
+        (client, Body::from(body))
+    } else {
+        (state.api_keys.authenticate_key(&parts.headers)?, body)
+    };
+
+    parts.extensions.insert(client);
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+// 请求频率限制结构
+struct RateLimit {
+    requests: HashMap<String, Vec<Instant>>,
+}
+
+impl RateLimit {
+    fn new() -> Self {
+        RateLimit {
+            requests: HashMap::new(),
+        }
+    }
+
+    fn is_allowed(&mut self, client: &str, limit: u32) -> bool {
+        let now = Instant::now();
+        let requests = self.requests.entry(client.to_string()).or_default();
+
+        requests.retain(|&time| now.duration_since(time) < Duration::from_secs(60));
+
+        if requests.len() >= limit as usize {
+            warn!("Rate limit exceeded for client: {}", client);
+            return false;
+        }
+
+        requests.push(now);
+        debug!(
+            "Request allowed for client: {} (count: {})",
+            client,
+            requests.len()
+        );
+        true
+    }
+}
+
+// hash-key 输出的配置项
+#[derive(Serialize)]
+struct HashedKeyEntry {
+    name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_prefix: Option<String>,
+    key_hash: String,
+    scopes: Vec<&'static str>,
+}
+
+// hash-key 子命令：生成新的 key（或对已有的 key 计算哈希）并输出配置项
+// 已有的 key 只从标准输入读取（参数为 -），避免出现在 shell 历史和进程列表中
+pub fn hash_key_command(arg: Option<String>) {
+    let (key, prefix) = match arg.as_deref() {
+        None => {
+            let (key, prefix) = generate_key();
+            (key, Some(prefix))
+        }
+        Some("-") => {
+            let mut key = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut key) {
+                eprintln!("Failed to read the key from stdin: {}", e);
+                std::process::exit(1);
+            }
+            let key = key.trim_end_matches(['\r', '\n']);
+            if key.is_empty() {
+                eprintln!("No key given on stdin");
+                std::process::exit(1);
+            }
+            (key.to_string(), None)
+        }
+        Some(_) => {
+            eprintln!("Usage: email-server hash-key [-]");
+            eprintln!(
+                "Without arguments a new key is generated, with - the key is read from stdin"
+            );
+            std::process::exit(1);
+        }
+    };
+    let generated = prefix.is_some();
+
+    let entry = HashedKeyEntry {
+        name: "CLIENT NAME",
+        key_prefix: prefix,
+        key_hash: key_hash(&key),
+        scopes: vec!["send"],
+    };
+
+    // 已有的 key 不再回显
+    if generated {
+        println!("API key: {}", key);
+        println!();
+    }
+    println!("Add this entry to server.api_keys in app_config.json, the key itself is not stored:");
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&entry).expect("entry is valid JSON")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    fn hashed(salt: &str, key: &str) -> String {
+        format!("sha256:{}:{}", salt, hash_key(salt, key))
+    }
+
+    #[test]
+    fn parse_hash_accepts_valid_hash() {
+        let stored = StoredKey::parse_hash("test", &hashed("salt", "secret")).unwrap();
+        assert!(matches!(stored, StoredKey::Hashed { ref salt, .. } if salt == "salt"));
+    }
+
+    #[test]
+    fn parse_hash_normalizes_uppercase_hex() {
+        let key_hash = format!(
+            "sha256:salt:{}",
+            hash_key("salt", "secret").to_ascii_uppercase()
+        );
+        let stored = StoredKey::parse_hash("test", &key_hash).unwrap();
+        assert!(stored.matches("secret"));
+    }
+
+    #[test]
+    fn parse_hash_rejects_invalid_hash() {
+        let hash = hash_key("salt", "secret");
+        for key_hash in [
+            format!("md5:salt:{}", hash),
+            format!("sha256:salt:{}", &hash[..63]),
+            format!("sha256:salt:{}0", hash),
+            format!("sha256:salt:{}g", &hash[..63]),
+            format!("sha256:{}", hash),
+            String::new(),
+        ] {
+            let err = StoredKey::parse_hash("test", &key_hash).err();
+            assert_eq!(
+                err.as_deref(),
+                Some("invalid key_hash of API key test"),
+                "{}",
+                key_hash
+            );
+        }
+    }
+
+    #[test]
+    fn matches_plain_key() {
+        let stored = StoredKey::Plain(hash_key("", "secret"));
+        assert!(stored.matches("secret"));
+        assert!(!stored.matches("secret2"));
+        assert!(!stored.matches(""));
+    }
+
+    #[test]
+    fn matches_hashed_key() {
+        let stored = StoredKey::parse_hash("test", &hashed("salt", "secret")).unwrap();
+        assert!(stored.matches("secret"));
+        assert!(!stored.matches("Secret"));
+        // 盐不同时相同的 key 也不匹配
+        let other = StoredKey::parse_hash("test", &hashed("other", "secret")).unwrap();
+        assert!(!other.matches("saltsecret"));
+        assert!(other.matches("secret"));
+    }
+
+    #[test]
+    fn domain_matches_domain_and_subdomains() {
+        let address = |s: &str| s.parse::<Address>().unwrap();
+        assert!(domain_matches(&address("a@example.com"), "example.com"));
+        assert!(domain_matches(
+            &address("a@mail.example.com"),
+            "example.com"
+        ));
+        assert!(domain_matches(
+            &address("a@Mail.EXAMPLE.com"),
+            "example.com"
+        ));
+        assert!(!domain_matches(&address("a@badexample.com"), "example.com"));
+        assert!(!domain_matches(
+            &address("a@example.com.evil.org"),
+            "example.com"
+        ));
+        assert!(!domain_matches(&address("a@com"), "example.com"));
+    }
+
+    fn signing_keys() -> ApiKeys {
+        let config = serde_json::from_value::<ServerConfig>(json!({
+            "signature_max_skew": 300,
+            "api_keys": [
+                {"name": "signer", "auth": "hmac", "hmac_secret": "s3cret", "scopes": ["send"]},
+                {"name": "plain", "key": "k", "scopes": ["send"]}
+            ]
+        }))
+        .unwrap();
+        ApiKeys::new(&config, &KeyStoreData::default()).unwrap()
+    }
+
+    fn sign(
+        secret: &str,
+        method: &str,
+        path: &str,
+        timestamp: i64,
+        nonce: &str,
+        body: &[u8],
+    ) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}\n{}\n{}\n{}\n", method, path, timestamp, nonce).as_bytes());
+        mac.update(body);
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    fn signed_headers(key_id: &str, timestamp: i64, nonce: &str, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            (KEY_ID_HEADER, key_id.to_string()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (NONCE_HEADER, nonce.to_string()),
+            (SIGNATURE_HEADER, signature.to_string()),
+        ] {
+            headers.insert(name, HeaderValue::from_str(&value).unwrap());
+        }
+        headers
+    }
+
+    fn rejection(result: Result<Arc<ApiClient>, EmailError>) -> String {
+        match result {
+            Err(EmailError::InvalidSignature(reason)) => reason,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(client) => panic!("request of {} was accepted", client.name),
+        }
+    }
+
+    const BODY: &[u8] = br#"{"to":"a@example.com"}"#;
+
+    #[test]
+    fn signature_over_canonical_string_is_accepted() {
+        let keys = signing_keys();
+        let now = Utc::now().timestamp();
+        let signature = sign("s3cret", "POST", "/send", now, "n1", BODY);
+        let headers = signed_headers("signer", now, "n1", &signature);
+        let client = keys
+            .authenticate_signature("POST", "/send", &headers, BODY)
+            .unwrap();
+        assert_eq!(client.name, "signer");
+
+        // 十六进制签名不区分大小写
+        let signature = sign("s3cret", "POST", "/send", now, "n2", BODY).to_ascii_uppercase();
+        let headers = signed_headers("signer", now, "n2", &signature);
+        assert!(keys
+            .authenticate_signature("POST", "/send", &headers, BODY)
+            .is_ok());
+    }
+
+    #[test]
+    fn signature_covers_method_path_and_body() {
+        let keys = signing_keys();
+        let now = Utc::now().timestamp();
+        let signature = sign("s3cret", "POST", "/send", now, "n1", BODY);
+        let headers = signed_headers("signer", now, "n1", &signature);
+        for (method, path, body) in [
+            ("PUT", "/send", BODY),
+            ("POST", "/send-batch", BODY),
+            ("POST", "/send", br#"{"to":"b@example.com"}"#.as_slice()),
+        ] {
+            let reason = rejection(keys.authenticate_signature(method, path, &headers, body));
+            assert_eq!(reason, "signature does not match");
+        }
+
+        let signature = sign("wrong", "POST", "/send", now, "n1", BODY);
+        let headers = signed_headers("signer", now, "n1", &signature);
+        let reason = rejection(keys.authenticate_signature("POST", "/send", &headers, BODY));
+        assert_eq!(reason, "signature does not match");
+    }
+
+    #[test]
+    fn timestamp_outside_skew_is_rejected() {
+        let keys = signing_keys();
+        let now = Utc::now().timestamp();
+        for timestamp in [now - 301, now + 301] {
+            let signature = sign("s3cret", "POST", "/send", timestamp, "n1", BODY);
+            let headers = signed_headers("signer", timestamp, "n1", &signature);
+            let reason = rejection(keys.authenticate_signature("POST", "/send", &headers, BODY));
+            assert_eq!(reason, "timestamp is outside the allowed window");
+        }
+
+        let timestamp = now - 250;
+        let signature = sign("s3cret", "POST", "/send", timestamp, "n1", BODY);
+        let headers = signed_headers("signer", timestamp, "n1", &signature);
+        assert!(keys
+            .authenticate_signature("POST", "/send", &headers, BODY)
+            .is_ok());
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let keys = signing_keys();
+        let now = Utc::now().timestamp();
+        let signature = sign("s3cret", "POST", "/send", now, "n1", BODY);
+        let headers = signed_headers("signer", now, "n1", &signature);
+        assert!(keys
+            .authenticate_signature("POST", "/send", &headers, BODY)
+            .is_ok());
+        let reason = rejection(keys.authenticate_signature("POST", "/send", &headers, BODY));
+        assert_eq!(reason, "nonce has already been used");
+    }
+
+    #[test]
+    fn invalid_signature_does_not_use_nonce() {
+        let keys = signing_keys();
+        let now = Utc::now().timestamp();
+        let headers = signed_headers("signer", now, "n1", &"0".repeat(64));
+        let reason = rejection(keys.authenticate_signature("POST", "/send", &headers, BODY));
+        assert_eq!(reason, "signature does not match");
+
+        let signature = sign("s3cret", "POST", "/send", now, "n1", BODY);
+        let headers = signed_headers("signer", now, "n1", &signature);
+        assert!(keys
+            .authenticate_signature("POST", "/send", &headers, BODY)
+            .is_ok());
+    }
+
+    #[test]
+    fn unknown_or_unsigned_key_id_is_rejected() {
+        let keys = signing_keys();
+        let now = Utc::now().timestamp();
+        let signature = sign("s3cret", "POST", "/send", now, "n1", BODY);
+        let headers = signed_headers("nobody", now, "n1", &signature);
+        let reason = rejection(keys.authenticate_signature("POST", "/send", &headers, BODY));
+        assert_eq!(reason, "unknown key id");
+
+        let headers = signed_headers("plain", now, "n1", &signature);
+        let reason = rejection(keys.authenticate_signature("POST", "/send", &headers, BODY));
+        assert_eq!(reason, "key id does not use request signing");
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{
+    auth::{ApiClient, Scope},
+    enqueue_email, one_or_many,
+    templates::TemplateStore,
+    ApiResponse, AppState, AttachmentSet, EmailError, EmailRequest,
+};
+use axum::{
+    extract::{Json, State},
+    Extension,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+// 批量发送请求，messages 和 template + recipients 二选一
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    #[serde(default)] // 完整的邮件列表，每一项与 /send-email 请求相同
+    messages: Vec<Value>,
+    #[serde(default)] // 邮件模板，subject 和正文按模板语法渲染，也可以引用服务端模板
+    template: Option<Value>,
+    #[serde(default)] // 模板模式下的收件人及变量
+    recipients: Vec<Value>,
+}
+
+// 模板模式下单个收件人的设置
+#[derive(Deserialize)]
+struct BatchRecipient {
+    #[serde(default, deserialize_with = "one_or_many")]
+    to: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    cc: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    bcc: Vec<String>,
+    #[serde(default)] // 收件人的模板语言，覆盖模板中的 locale
+    locale: Option<String>,
+    #[serde(default)]
+    variables: Map<String, Value>,
+}
+
+// 批量发送处理函数，逐条校验并返回每一项的结果
+pub async fn send_batch(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Json(batch): Json<BatchRequest>,
+) -> Result<Json<ApiResponse>, EmailError> {
+    client.require(Scope::Batch)?;
+
+    let items = expand_batch(&state.templates, batch)?;
+    let max_batch_size = state.app_config.server.max_batch_size;
+    if items.len() > max_batch_size {
+        warn!("Batch of {} messages exceeds the limit", items.len());
+        return Err(EmailError::InvalidRequest(format!(
+            "Batch contains {} messages, the limit is {}",
+            items.len(),
+            max_batch_size
+        )));
+    }
+    info!(
+        "Processing batch of {} message(s) from client {}",
+        items.len(),
+        client.name
+    );
+
+    let mut results = Vec::with_capacity(items.len());
+    let mut queued = 0;
+    for (index, item) in items.into_iter().enumerate() {
+        let result = match item {
+            Ok(req) => send_item(&state, &client, req).await,
+            Err(e) => Err(e),
+        };
+        results.push(match result {
+            Ok(response) => {
+                queued += 1;
+                response
+            }
+            Err(e) => {
+                debug!("Batch item {} failed: {}", index, e);
+                let (_, message) = e.status_and_message();
+                ApiResponse {
+                    status: "error".to_string(),
+                    message,
+                    errors: e.field_errors(),
+                    ..Default::default()
+                }
+            }
+        });
+    }
+
+    let total = results.len();
+    info!("Batch processed: {} of {} message(s) queued", queued, total);
+    Ok(Json(ApiResponse {
+        status: match queued {
+            0 if total > 0 => "error",
+            n if n < total => "partial",
+            _ => "success",
+        }
+        .to_string(),
+        message: format!("{} of {} messages queued", queued, total),
+        results,
+        ..Default::default()
+    }))
+}
+
+// 每一项单独计入频率限制
+async fn send_item(
+    state: &AppState,
+    client: &ApiClient,
+    req: EmailRequest,
+) -> Result<ApiResponse, EmailError> {
+    state.api_keys.check_rate_limit(client)?;
+    if req.dry_run {
+        return Err(EmailError::InvalidField {
+            field: "dry_run".to_string(),
+            reason: "not supported in batch requests".to_string(),
+        });
+    }
+
+    let mut attachments = AttachmentSet::new(&state.app_config.server);
+    for attachment in &req.attachments {
+        attachments.push_base64(attachment)?;
+    }
+
+    enqueue_email(state, client, req, attachments.into_inner()).await
+}
+
+// 将批量请求展开为单独的邮件请求
+fn expand_batch(
+    templates: &TemplateStore,
+    batch: BatchRequest,
+) -> Result<Vec<Result<EmailRequest, EmailError>>, EmailError> {
+    match (batch.messages.is_empty(), batch.template) {
+        (false, None) if batch.recipients.is_empty() => Ok(batch
+            .messages
+            .into_iter()
+            .map(|message| {
+                serde_json::from_value::<EmailRequest>(message)
+                    .map_err(|e| EmailError::InvalidRequest(format!("Invalid message: {}", e)))
+            })
+            .collect()),
+        (true, Some(template)) => {
+            let template = serde_json::from_value::<EmailRequest>(template).map_err(|e| {
+                warn!("Invalid batch template: {}", e);
+                EmailError::InvalidRequest(format!("Invalid template: {}", e))
+            })?;
+            Ok(batch
+                .recipients
+                .into_iter()
+                .map(|recipient| {
+                    serde_json::from_value::<BatchRecipient>(recipient)
+                        .map_err(|e| {
+                            EmailError::InvalidRequest(format!("Invalid recipient: {}", e))
+                        })
+                        .and_then(|recipient| personalize(templates, &template, recipient))
+                })
+                .collect())
+        }
+        _ => {
+            warn!("Batch request has neither messages nor template with recipients");
+            Err(EmailError::InvalidRequest(
+                "Provide either messages or template with recipients".to_string(),
+            ))
+        }
+    }
+}
+
+// 使用收件人的地址和变量生成个性化邮件
+fn personalize(
+    templates: &TemplateStore,
+    template: &EmailRequest,
+    recipient: BatchRecipient,
+) -> Result<EmailRequest, EmailError> {
+    let mut req = template.clone();
+    if !recipient.to.is_empty() {
+        req.to = recipient.to;
+    }
+    if !recipient.cc.is_empty() {
+        req.cc = recipient.cc;
+    }
+    if !recipient.bcc.is_empty() {
+        req.bcc = recipient.bcc;
+    }
+
+    if recipient.locale.is_some() {
+        req.locale = recipient.locale;
+    }
+
+    // 收件人的变量覆盖模板中的同名变量
+    req.variables.extend(recipient.variables);
+    let variables = &req.variables;
+    if let Some(subject) = &req.subject {
+        req.subject = Some(templates.render_str(subject, variables, false)?);
+    }
+    req.body = templates.render_str(&req.body, variables, false)?;
+    if let Some(text_body) = &req.text_body {
+        req.text_body = Some(templates.render_str(text_body, variables, false)?);
+    }
+    if let Some(markdown_body) = &req.markdown_body {
+        req.markdown_body = Some(templates.render_str(markdown_body, variables, false)?);
+    }
+    if let Some(html_body) = &req.html_body {
+        req.html_body = Some(templates.render_str(html_body, variables, true)?);
+    }
+    Ok(req)
+}
@@ -0,0 +1,122 @@
+use lettre::{
+    message::{
+        dkim::{
+            DkimCanonicalization, DkimCanonicalizationType, DkimConfig, DkimSigningAlgorithm,
+            DkimSigningKey,
+        },
+        header::HeaderName,
+    },
+    Message,
+};
+use serde::Deserialize;
+use std::cmp::Reverse;
+use tracing::{debug, info};
+
+// DKIM 签名配置
+#[derive(Debug, Deserialize, Clone)]
+pub struct DkimSettings {
+    pub domain: String,   // 签名域名，发件人域名为该域名或其子域名时使用
+    pub selector: String, // DNS 中公钥记录的选择器
+    pub private_key_path: String,
+    #[serde(default)] // 签名算法，rsa 或 ed25519
+    pub algorithm: DkimAlgorithm,
+    #[serde(default = "default_dkim_headers")] // 参与签名的邮件头
+    pub headers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimAlgorithm {
+    #[default]
+    Rsa,
+    Ed25519,
+}
+
+// 默认签名的邮件头
+fn default_dkim_headers() -> Vec<String> {
+    [
+        "From",
+        "Reply-To",
+        "Subject",
+        "Date",
+        "To",
+        "Cc",
+        "Message-ID",
+        "MIME-Version",
+        "Content-Type",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()
+}
+
+// DKIM 签名器，按发件人域名选择签名密钥
+pub struct DkimSigner {
+    keys: Vec<(String, DkimConfig)>,
+}
+
+impl DkimSigner {
+    // 读取所有私钥，任一配置无效时返回错误
+    pub fn load(settings: &[DkimSettings]) -> Result<Self, String> {
+        let mut keys = Vec::with_capacity(settings.len());
+        for settings in settings {
+            let private_key = std::fs::read_to_string(&settings.private_key_path)
+                .map_err(|e| format!("failed to read {}: {}", settings.private_key_path, e))?;
+            let algorithm = match settings.algorithm {
+                DkimAlgorithm::Rsa => DkimSigningAlgorithm::Rsa,
+                DkimAlgorithm::Ed25519 => DkimSigningAlgorithm::Ed25519,
+            };
+            let signing_key = DkimSigningKey::new(private_key.trim(), algorithm)
+                .map_err(|e| format!("invalid private key {}: {}", settings.private_key_path, e))?;
+            let headers = settings
+                .headers
+                .iter()
+                .map(|name| {
+                    HeaderName::new_from_ascii(name.clone())
+                        .map_err(|_| format!("invalid header name: {}", name))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // relaxed 规范化可以容忍中继服务器对空白和大小写的改动
+            let config = DkimConfig::new(
+                settings.selector.clone(),
+                settings.domain.clone(),
+                signing_key,
+                headers,
+                DkimCanonicalization {
+                    header: DkimCanonicalizationType::Relaxed,
+                    body: DkimCanonicalizationType::Relaxed,
+                },
+            );
+            info!(
+                "Loaded DKIM key for {} (selector {})",
+                settings.domain, settings.selector
+            );
+            keys.push((settings.domain.to_ascii_lowercase(), config));
+        }
+        // 子域名的密钥优先于上级域名
+        keys.sort_by_key(|(domain, _)| Reverse(domain.len()));
+        Ok(DkimSigner { keys })
+    }
+
+    // 使用与发件人域名匹配的密钥签名，没有匹配的密钥时不签名
+    pub fn sign(&self, message: &mut Message, from_domain: &str) {
+        let from_domain = from_domain.to_ascii_lowercase();
+        let key = self.keys.iter().find(|(domain, _)| {
+            from_domain == *domain
+                || from_domain
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        });
+        match key {
+            Some((domain, config)) => {
+                debug!("Signing message with DKIM key for {}", domain);
+                message.sign(config);
+            }
+            None if !self.keys.is_empty() => {
+                debug!("No DKIM key for {}, message is not signed", from_domain);
+            }
+            None => {}
+        }
+    }
+}
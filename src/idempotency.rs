@@ -0,0 +1,392 @@
+use crate::{auth::ApiClient, queue::write_atomic, request_body_limit, AppState, EmailError};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{debug, info, warn};
+
+// 幂等键的最大长度
+const MAX_KEY_LENGTH: usize = 255;
+
+// 已保存的响应，用于重复请求时原样返回
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+struct Entry {
+    body_hash: String,
+    created_at: DateTime<Utc>,
+    response: Option<StoredResponse>, // 为空表示请求仍在处理中
+}
+
+// 幂等键文件的内容，只保存已完成的请求
+#[derive(Serialize, Deserialize)]
+struct EntryFile {
+    key: String,
+    body_hash: String,
+    created_at: DateTime<Utc>,
+    status: u16,
+    content_type: Option<String>,
+    body: String, // Base64 编码的响应体
+}
+
+impl EntryFile {
+    fn into_entry(self) -> Result<(String, Entry), String> {
+        let status = StatusCode::from_u16(self.status).map_err(|e| e.to_string())?;
+        let content_type = self
+            .content_type
+            .map(HeaderValue::try_from)
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        let body = BASE64_STANDARD
+            .decode(self.body)
+            .map_err(|e| e.to_string())?;
+        Ok((
+            self.key,
+            Entry {
+                body_hash: self.body_hash,
+                created_at: self.created_at,
+                response: Some(StoredResponse {
+                    status,
+                    content_type,
+                    body: body.into(),
+                }),
+            },
+        ))
+    }
+}
+
+// 查询幂等键的结果
+enum Lookup {
+    New,
+    Replay(StoredResponse),
+    InProgress,
+    Conflict,
+}
+
+// 幂等键存储，已完成的请求每个键保存为一个文件，重启后仍然有效
+pub struct IdempotencyStore {
+    dir: PathBuf,
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl IdempotencyStore {
+    // 打开存储目录并加载未过期的幂等键，过期的文件直接删除
+    pub fn open(dir: &Path, window: Duration) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut entries = HashMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| {
+                    serde_json::from_slice::<EntryFile>(&data).map_err(|e| e.to_string())
+                })
+                .and_then(EntryFile::into_entry)
+            {
+                Ok((_, entry)) if is_expired(&entry, window) => std::fs::remove_file(&path)?,
+                Ok((key, entry)) => {
+                    entries.insert(key, entry);
+                }
+                Err(e) => warn!(
+                    "Skipping unreadable idempotency entry {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        info!("Loaded {} idempotency key(s)", entries.len());
+
+        Ok(IdempotencyStore {
+            dir: dir.to_path_buf(),
+            window,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    // 键可能包含任意字符，文件名使用其哈希
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{:x}.json", Sha256::digest(key.as_bytes())))
+    }
+
+    // 查询幂等键，不存在时占用该键
+    async fn begin(&self, key: &str, body_hash: &str) -> Lookup {
+        let (lookup, expired) = self.lookup(key, body_hash);
+        for key in expired {
+            if let Err(e) = tokio::fs::remove_file(self.path(&key)).await {
+                warn!("Failed to remove expired idempotency key: {}", e);
+            }
+        }
+        lookup
+    }
+
+    // 返回查询结果和需要删除文件的过期键
+    fn lookup(&self, key: &str, body_hash: &str) -> (Lookup, Vec<String>) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut expired = Vec::new();
+        entries.retain(|key, entry| {
+            if !is_expired(entry, self.window) {
+                return true;
+            }
+            if entry.response.is_some() {
+                expired.push(key.clone());
+            }
+            false
+        });
+
+        let lookup = match entries.get(key) {
+            Some(entry) if entry.body_hash != body_hash => Lookup::Conflict,
+            Some(Entry {
+                response: Some(response),
+                ..
+            }) => Lookup::Replay(response.clone()),
+            Some(_) => Lookup::InProgress,
+            None => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        body_hash: body_hash.to_string(),
+                        created_at: Utc::now(),
+                        response: None,
+                    },
+                );
+                Lookup::New
+            }
+        };
+        (lookup, expired)
+    }
+
+    // 保存响应，写入文件失败时仍在内存中保留，只是重启后失效
+    async fn complete(&self, key: &str, response: StoredResponse) {
+        let file = {
+            let mut entries = self.entries.lock().unwrap();
+            let Some(entry) = entries.get_mut(key) else {
+                return;
+            };
+            entry.response = Some(response.clone());
+            EntryFile {
+                key: key.to_string(),
+                body_hash: entry.body_hash.clone(),
+                created_at: entry.created_at,
+                status: response.status.as_u16(),
+                content_type: response
+                    .content_type
+                    .and_then(|value| value.to_str().ok().map(str::to_string)),
+                body: BASE64_STANDARD.encode(&response.body),
+            }
+        };
+        let data = serde_json::to_vec(&file).expect("entry is valid JSON");
+        if let Err(e) = write_atomic(&self.path(key), &data).await {
+            warn!("Failed to store idempotency key: {}", e);
+        }
+    }
+
+    // 释放幂等键，允许客户端重试
+    fn release(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+// 请求未完成（出错或被取消）时释放幂等键
+struct PendingKey<'a> {
+    store: &'a IdempotencyStore,
+    key: &'a str,
+    completed: bool,
+}
+
+impl PendingKey<'_> {
+    async fn complete(mut self, response: StoredResponse) {
+        self.store.complete(self.key, response).await;
+        self.completed = true;
+    }
+}
+
+fn is_expired(entry: &Entry, window: Duration) -> bool {
+    (Utc::now() - entry.created_at).to_std().unwrap_or_default() >= window
+}
+
+impl Drop for PendingKey<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.store.release(self.key);
+        }
+    }
+}
+
+// 幂等中间件，处理带 Idempotency-Key 头的请求
+pub async fn idempotency(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, EmailError> {
+    let Some(key) = request.headers().get("Idempotency-Key") else {
+        return Ok(next.run(request).await);
+    };
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_KEY_LENGTH)
+        .ok_or_else(|| {
+            warn!("Invalid Idempotency-Key header");
+            EmailError::InvalidHeader {
+                field: "Idempotency-Key".to_string(),
+                reason: format!("must be 1 to {} visible ASCII characters", MAX_KEY_LENGTH),
+            }
+        })?
+        .to_string();
+
+    // 认证中间件已识别客户端，幂等键按客户端隔离
+    let client = request
+        .extensions()
+        .get::<Arc<ApiClient>>()
+        .cloned()
+        .ok_or(EmailError::MissingApiKey)?;
+
+    // 读取请求体并计算哈希
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, request_body_limit(&state.app_config.server))
+        .await
+        .map_err(|e| {
+            warn!("Failed to read request body: {}", e);
+            EmailError::InvalidRequest(format!("Failed to read request body: {}", e))
+        })?;
+    let body_hash = format!("{:x}", Sha256::digest(&body));
+    // 不同客户端的幂等键互不影响，也不能读取彼此的响应
+    let store_key = format!("{} {} {}", client.name, parts.uri.path(), key);
+
+    let pending = match state.idempotency.begin(&store_key, &body_hash).await {
+        Lookup::New => PendingKey {
+            store: &state.idempotency,
+            key: &store_key,
+            completed: false,
+        },
+        Lookup::Replay(stored) => {
+            info!("Replaying response for Idempotency-Key {}", key);
+            let mut response = (stored.status, stored.body).into_response();
+            if let Some(content_type) = stored.content_type {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_TYPE, content_type);
+            }
+            response
+                .headers_mut()
+                .insert("Idempotent-Replayed", HeaderValue::from_static("true"));
+            return Ok(response);
+        }
+        Lookup::InProgress => {
+            warn!("Request with Idempotency-Key {} is still in progress", key);
+            return Err(EmailError::IdempotencyInProgress);
+        }
+        Lookup::Conflict => {
+            warn!("Idempotency-Key {} reused with a different body", key);
+            return Err(EmailError::IdempotencyConflict);
+        }
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    // 服务端错误和未真正处理的请求不保存，允许客户端重试
+    let status = response.status();
+    if status.is_server_error()
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::TOO_MANY_REQUESTS
+    {
+        debug!(
+            "Not storing {} response for Idempotency-Key {}",
+            status, key
+        );
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to buffer response body: {}", e);
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+    pending
+        .complete(StoredResponse {
+            status,
+            content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+            body: body.clone(),
+        })
+        .await;
+    debug!("Stored response for Idempotency-Key {}", key);
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> StoredResponse {
+        StoredResponse {
+            status: StatusCode::ACCEPTED,
+            content_type: Some(HeaderValue::from_static("application/json")),
+            body: Bytes::from_static(br#"{"status":"success"}"#),
+        }
+    }
+
+    #[tokio::test]
+    async fn completed_keys_survive_reopen() {
+        let dir = std::env::temp_dir().join(format!("idempotency-{}", uuid::Uuid::new_v4()));
+        let window = Duration::from_secs(3600);
+        let store = IdempotencyStore::open(&dir, window).unwrap();
+        assert!(matches!(store.begin("done", "h1").await, Lookup::New));
+        store.complete("done", response()).await;
+        assert!(matches!(store.begin("pending", "h2").await, Lookup::New));
+
+        let store = IdempotencyStore::open(&dir, window).unwrap();
+        match store.begin("done", "h1").await {
+            Lookup::Replay(stored) => {
+                assert_eq!(stored.status, StatusCode::ACCEPTED);
+                assert_eq!(stored.content_type, response().content_type);
+                assert_eq!(stored.body, response().body);
+            }
+            _ => panic!("expected a replay"),
+        }
+        assert!(matches!(store.begin("done", "h3").await, Lookup::Conflict));
+        // 未完成的请求不保存，重启后可以重试
+        assert!(matches!(store.begin("pending", "h2").await, Lookup::New));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_removed() {
+        let dir = std::env::temp_dir().join(format!("idempotency-{}", uuid::Uuid::new_v4()));
+        let store = IdempotencyStore::open(&dir, Duration::from_secs(3600)).unwrap();
+        store.begin("done", "h1").await;
+        store.complete("done", response()).await;
+        assert!(store.path("done").exists());
+
+        let store = IdempotencyStore::open(&dir, Duration::ZERO).unwrap();
+        assert!(!store.path("done").exists());
+        assert!(matches!(store.begin("done", "h1").await, Lookup::New));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,401 @@
+use crate::EmailError;
+use jsonwebtoken::{
+    decode, decode_header,
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+// JWT 配置，令牌放在 Authorization: Bearer 头中
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwtConfig {
+    #[serde(default)] // 配置中直接给出的密钥
+    pub keys: Vec<JwtKeyConfig>,
+    #[serde(default)] // 本地 JWKS 文件，与 keys 合并使用
+    pub jwks_file: Option<String>,
+    #[serde(default)] // 接受的签发者（iss），未配置时不检查
+    pub issuer: Option<Vec<String>>,
+    #[serde(default)] // 接受的受众（aud），配置后令牌必须包含其中之一
+    pub audience: Option<Vec<String>>,
+    #[serde(default = "default_leeway")] // 检查 exp 时允许的时间偏差（秒）
+    pub leeway: u64,
+    #[serde(default = "default_jwt_rate_limit")] // 每个 sub 每分钟最多请求数
+    pub rate_limit: u32,
+}
+
+// 默认时间偏差：60 秒
+fn default_leeway() -> u64 {
+    60
+}
+
+// 默认频率限制：每分钟 10 次
+fn default_jwt_rate_limit() -> u32 {
+    10
+}
+
+// 单个验证密钥
+#[derive(Debug, Deserialize, Clone)]
+pub struct JwtKeyConfig {
+    #[serde(default)] // 对应令牌头部的 kid，未配置时可验证任何 kid 的令牌
+    pub kid: Option<String>,
+    pub algorithm: JwtAlgorithm,
+    #[serde(default)] // HS256 使用的共享密钥
+    pub secret: Option<String>,
+    #[serde(default)] // RS256 和 EdDSA 使用的公钥文件（PEM）
+    pub public_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+impl From<JwtAlgorithm> for Algorithm {
+    fn from(algorithm: JwtAlgorithm) -> Self {
+        match algorithm {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+            JwtAlgorithm::EdDSA => Algorithm::EdDSA,
+        }
+    }
+}
+
+// 令牌中的声明，权限相关的声明与 api_keys 的配置项同名
+#[derive(Deserialize)]
+pub struct TokenClaims {
+    pub sub: String,
+    #[serde(default, alias = "scopes")] // 空格分隔的字符串或数组
+    pub scope: Option<ScopeClaim>,
+    #[serde(default)]
+    pub accounts: Option<Vec<String>>,
+    #[serde(default)]
+    pub allowed_from: Option<Vec<String>>,
+    #[serde(default)]
+    pub allowed_recipient_domains: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ScopeClaim {
+    Joined(String),
+    List(Vec<String>),
+}
+
+impl ScopeClaim {
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            ScopeClaim::Joined(scopes) => scopes.split_whitespace().collect(),
+            ScopeClaim::List(scopes) => scopes.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+struct JwtKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    validation: Validation,
+}
+
+// 验证 Bearer 令牌
+pub struct JwtVerifier {
+    keys: Vec<JwtKey>,
+    pub rate_limit: u32,
+}
+
+impl JwtVerifier {
+    // 加载配置中的密钥和 JWKS 文件，密钥无效时返回错误
+    pub fn new(config: &JwtConfig) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        for key in &config.keys {
+            let kid = key.kid.as_deref().unwrap_or("(no kid)");
+            let decoding_key = match key.algorithm {
+                JwtAlgorithm::HS256 => match &key.secret {
+                    Some(secret) if !secret.is_empty() => {
+                        DecodingKey::from_secret(secret.as_bytes())
+                    }
+                    _ => return Err(format!("JWT key {} needs a secret", kid)),
+                },
+                JwtAlgorithm::RS256 | JwtAlgorithm::EdDSA => {
+                    let path = key
+                        .public_key
+                        .as_ref()
+                        .ok_or_else(|| format!("JWT key {} needs a public_key", kid))?;
+                    let pem = std::fs::read(path)
+                        .map_err(|e| format!("failed to read {}: {}", path, e))?;
+                    match key.algorithm {
+                        JwtAlgorithm::RS256 => DecodingKey::from_rsa_pem(&pem),
+                        _ => DecodingKey::from_ed_pem(&pem),
+                    }
+                    .map_err(|e| format!("invalid public key {}: {}", path, e))?
+                }
+            };
+            keys.push(JwtKey {
+                kid: key.kid.clone(),
+                algorithm: key.algorithm.into(),
+                key: decoding_key,
+                validation: validation(config, key.algorithm.into()),
+            });
+        }
+
+        if let Some(path) = &config.jwks_file {
+            let jwks = std::fs::read_to_string(path)
+                .map_err(|e| format!("failed to read {}: {}", path, e))?;
+            let jwks = serde_json::from_str::<JwkSet>(&jwks)
+                .map_err(|e| format!("invalid JWKS file {}: {}", path, e))?;
+            for jwk in &jwks.keys {
+                let kid = jwk.common.key_id.as_deref().unwrap_or("(no kid)");
+                // 未指定 alg 时按密钥类型推断
+                let algorithm = match (jwk.common.key_algorithm, &jwk.algorithm) {
+                    (Some(KeyAlgorithm::HS256), _) => Algorithm::HS256,
+                    (Some(KeyAlgorithm::RS256), _) => Algorithm::RS256,
+                    (Some(KeyAlgorithm::EdDSA), _) => Algorithm::EdDSA,
+                    (None, AlgorithmParameters::OctetKey(_)) => Algorithm::HS256,
+                    (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+                    (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+                    _ => {
+                        warn!("Skipping JWKS key {} with unsupported algorithm", kid);
+                        continue;
+                    }
+                };
+                let key = DecodingKey::from_jwk(jwk)
+                    .map_err(|e| format!("invalid JWKS key {} in {}: {}", kid, path, e))?;
+                keys.push(JwtKey {
+                    kid: jwk.common.key_id.clone(),
+                    algorithm,
+                    key,
+                    validation: validation(config, algorithm),
+                });
+            }
+        }
+
+        if keys.is_empty() {
+            return Err("jwt is configured without any key".to_string());
+        }
+        info!("Loaded {} JWT verification key(s)", keys.len());
+        Ok(JwtVerifier {
+            keys,
+            rate_limit: config.rate_limit,
+        })
+    }
+
+    // 验证令牌并返回声明，签名正确后的错误不再尝试其他密钥
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, EmailError> {
+        let header = decode_header(token).map_err(|e| {
+            warn!("Malformed bearer token: {}", e);
+            EmailError::InvalidToken("malformed token".to_string())
+        })?;
+
+        // 令牌带 kid 时只尝试 kid 相同或未配置 kid 的密钥
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithm == header.alg
+                && match (&key.kid, &header.kid) {
+                    (Some(kid), Some(token_kid)) => kid == token_kid,
+                    _ => true,
+                }
+        });
+        for key in candidates {
+            match decode::<TokenClaims>(token, &key.key, &key.validation) {
+                Ok(data) => {
+                    debug!("Verified bearer token of {}", data.claims.sub);
+                    return Ok(data.claims);
+                }
+                Err(e) if *e.kind() == ErrorKind::InvalidSignature => continue,
+                Err(e) => {
+                    warn!("Rejected bearer token: {}", e);
+                    return Err(match e.kind() {
+                        ErrorKind::ExpiredSignature => EmailError::TokenExpired,
+                        ErrorKind::InvalidAudience => EmailError::TokenAudience,
+                        ErrorKind::MissingRequiredClaim(claim) if claim == "aud" => {
+                            EmailError::TokenAudience
+                        }
+                        _ => EmailError::InvalidToken(e.to_string()),
+                    });
+                }
+            }
+        }
+
+        warn!(
+            "No JWT key verifies the bearer token (alg {:?}, kid {:?})",
+            header.alg, header.kid
+        );
+        Err(EmailError::InvalidToken(
+            "no configured key matches the token".to_string(),
+        ))
+    }
+}
+
+// sub 和 exp 必须存在，配置了 audience 时 aud 也必须存在
+fn validation(config: &JwtConfig, algorithm: Algorithm) -> Validation {
+    let mut validation = Validation::new(algorithm);
+    validation.leeway = config.leeway;
+    let mut required = vec!["exp", "sub"];
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(issuer);
+        required.push("iss");
+    }
+    match &config.audience {
+        Some(audience) => {
+            validation.set_audience(audience);
+            required.push("aud");
+        }
+        None => validation.validate_aud = false,
+    }
+    validation.set_required_spec_claims(&required);
+    validation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    const RSA_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA9p3YYzyvVNEEKAKG0ZgM
+aKxmKPRAu/c2zda/suJEYOc2PX/8HAHaQp6FG528RU/nR7GJvAYvuExKy3Qz5o9i
+JA2Bet8TQr4h9iNXqzpmMnefkBjNT+PP+4ZcsgpCJZk+1uh7tOQfA+Kr3W4SAmm0
+MLPRFy+D5uzuj6risib3qdsoH1EpZ0H7jSlZiRYunJsQFYi4sON3S26bqU4ZGSfH
+wFh8QXRYOsyFq5LVaQTfDbFcwo1pO8bkDjBLT2h5zUpGehpeNnomKD9yYdR2xMyP
+UdT1ay7H7+SUBJId6bb/Cu1fwmUjIAqM5ZhhpHpjB1CQSp+4YaJLsGKlg90dSj0K
+JQIDAQAB
+-----END PUBLIC KEY-----
+";
+
+    fn verifier(keys: Value) -> JwtVerifier {
+        let config = serde_json::from_value::<JwtConfig>(json!({
+            "keys": keys,
+            "audience": ["email-server"],
+        }))
+        .unwrap();
+        JwtVerifier::new(&config).unwrap()
+    }
+
+    fn hs_verifier() -> JwtVerifier {
+        verifier(json!([{"algorithm": "HS256", "secret": "jwtsecret"}]))
+    }
+
+    fn claims() -> Value {
+        json!({
+            "sub": "billing",
+            "aud": "email-server",
+            "exp": chrono::Utc::now().timestamp() + 600,
+            "scope": "send read-status",
+        })
+    }
+
+    fn sign(kid: Option<&str>, secret: &[u8], claims: &Value) -> String {
+        let header = Header {
+            kid: kid.map(str::to_string),
+            ..Header::new(Algorithm::HS256)
+        };
+        encode(&header, claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn rejection(result: Result<TokenClaims, EmailError>) -> EmailError {
+        match result {
+            Err(e) => e,
+            Ok(claims) => panic!("token of {} was accepted", claims.sub),
+        }
+    }
+
+    fn without(claim: &str) -> Value {
+        let mut claims = claims();
+        claims.as_object_mut().unwrap().remove(claim);
+        claims
+    }
+
+    #[test]
+    fn hs256_token_is_accepted() {
+        let token = sign(None, b"jwtsecret", &claims());
+        let claims = hs_verifier().verify(&token).unwrap();
+        assert_eq!(claims.sub, "billing");
+        assert_eq!(
+            claims.scope.as_ref().map(ScopeClaim::names),
+            Some(vec!["send", "read-status"])
+        );
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected() {
+        let token = sign(None, b"othersecret", &claims());
+        let error = rejection(hs_verifier().verify(&token));
+        assert!(matches!(error, EmailError::InvalidToken(_)));
+    }
+
+    #[test]
+    fn hs256_token_does_not_verify_against_rsa_key() {
+        // 用公钥作为 HMAC 密钥签名的令牌不能通过 RS256 密钥的验证
+        let path = std::env::temp_dir().join(format!("jwt-rsa-{}.pem", uuid::Uuid::new_v4()));
+        std::fs::write(&path, RSA_PUBLIC_KEY).unwrap();
+        let verifier = verifier(json!([
+            {"algorithm": "RS256", "public_key": path.to_string_lossy()}
+        ]));
+        std::fs::remove_file(&path).unwrap();
+
+        let token = sign(None, RSA_PUBLIC_KEY.as_bytes(), &claims());
+        let error = rejection(verifier.verify(&token));
+        assert!(
+            matches!(error, EmailError::InvalidToken(ref reason) if reason == "no configured key matches the token")
+        );
+    }
+
+    #[test]
+    fn kid_selects_the_key() {
+        let verifier = verifier(json!([
+            {"kid": "a", "algorithm": "HS256", "secret": "secret-a"},
+            {"kid": "b", "algorithm": "HS256", "secret": "secret-b"}
+        ]));
+        assert!(verifier
+            .verify(&sign(Some("b"), b"secret-b", &claims()))
+            .is_ok());
+        // 令牌没有 kid 时尝试所有同算法的密钥
+        assert!(verifier.verify(&sign(None, b"secret-b", &claims())).is_ok());
+        // kid 不同的密钥即使能验证签名也不会被尝试
+        let error = rejection(verifier.verify(&sign(Some("a"), b"secret-b", &claims())));
+        assert!(matches!(error, EmailError::InvalidToken(_)));
+        let error = rejection(verifier.verify(&sign(Some("c"), b"secret-a", &claims())));
+        assert!(matches!(error, EmailError::InvalidToken(_)));
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let mut claims = claims();
+        // 超出 60 秒的默认偏差
+        claims["exp"] = json!(chrono::Utc::now().timestamp() - 120);
+        let error = rejection(hs_verifier().verify(&sign(None, b"jwtsecret", &claims)));
+        assert!(matches!(error, EmailError::TokenExpired));
+    }
+
+    #[test]
+    fn wrong_or_missing_audience_is_rejected() {
+        let mut claims = claims();
+        claims["aud"] = json!("other-service");
+        let error = rejection(hs_verifier().verify(&sign(None, b"jwtsecret", &claims)));
+        assert!(matches!(error, EmailError::TokenAudience));
+
+        let error = rejection(hs_verifier().verify(&sign(None, b"jwtsecret", &without("aud"))));
+        assert!(matches!(error, EmailError::TokenAudience));
+    }
+
+    #[test]
+    fn missing_sub_or_exp_is_rejected() {
+        for claim in ["sub", "exp"] {
+            let token = sign(None, b"jwtsecret", &without(claim));
+            let error = rejection(hs_verifier().verify(&token));
+            assert!(matches!(error, EmailError::InvalidToken(_)), "{}", claim);
+        }
+    }
+
+    #[test]
+    fn malformed_token_is_rejected() {
+        let error = rejection(hs_verifier().verify("not-a-token"));
+        assert!(
+            matches!(error, EmailError::InvalidToken(ref reason) if reason == "malformed token")
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use crate::{
+    auth::{
+        default_rate_limit, generate_key, key_hash, ApiClient, ApiKeyConfig, ApiKeys, AuthMethod,
+        Scope,
+    },
+    queue::write_atomic,
+    ApiResponse, AppState, EmailError,
+};
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{io, path::PathBuf, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+// key 名称的最大长度
+const MAX_NAME_LENGTH: usize = 100;
+
+// 轮换过渡期的上限：30 天
+pub const MAX_ROTATION_OVERLAP: u64 = 30 * 24 * 3600;
+
+// 运行时创建的 key，只保存哈希
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ManagedKey {
+    pub name: String,
+    pub key_prefix: String,
+    pub key_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] // 轮换前的 key，过渡期内仍然有效
+    pub previous_key: Option<PreviousKey>,
+    pub scopes: Vec<Scope>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accounts: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_from: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_recipient_domains: Option<Vec<String>>,
+    pub rate_limit: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub disabled: bool,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PreviousKey {
+    pub key_prefix: String,
+    pub key_hash: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ManagedKey {
+    // 转换为与 api_keys 相同的配置，使用相同的权限模型
+    pub fn config(&self) -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: self.name.clone(),
+            auth: AuthMethod::ApiKey,
+            key: None,
+            key_hash: Some(self.key_hash.clone()),
+            key_prefix: Some(self.key_prefix.clone()),
+            hmac_secret: None,
+            expires_at: self.expires_at,
+            scopes: self.scopes.clone(),
+            accounts: self.accounts.clone(),
+            allowed_from: self.allowed_from.clone(),
+            allowed_recipient_domains: self.allowed_recipient_domains.clone(),
+            rate_limit: self.rate_limit,
+        }
+    }
+}
+
+// key 存储文件的内容
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct KeyStoreData {
+    #[serde(default)] // 运行时创建的 key
+    pub keys: Vec<ManagedKey>,
+    #[serde(default)] // 已停用的 app_config.json 中的 key
+    pub disabled_config_keys: Vec<String>,
+}
+
+// 读取 key 存储文件，文件不存在时返回空的存储
+pub fn load(path: &str) -> Result<KeyStoreData, String> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| format!("invalid {}: {}", path, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(KeyStoreData::default()),
+        Err(e) => Err(format!("failed to read {}: {}", path, e)),
+    }
+}
+
+// 运行时创建的 key 和停用状态的存储，修改后写入文件并更新 ApiKeys
+pub struct KeyStore {
+    path: PathBuf,
+    data: Mutex<KeyStoreData>, // 持有锁直到写入完成，避免并发修改互相覆盖
+}
+
+impl KeyStore {
+    pub fn new(path: &str, data: KeyStoreData) -> Self {
+        KeyStore {
+            path: PathBuf::from(path),
+            data: Mutex::new(data),
+        }
+    }
+
+    pub async fn get(&self) -> KeyStoreData {
+        self.data.lock().await.clone()
+    }
+
+    // 在副本上修改，写入文件成功后才生效
+    async fn update<T>(
+        &self,
+        api_keys: &ApiKeys,
+        change: impl FnOnce(&mut KeyStoreData) -> Result<T, EmailError>,
+    ) -> Result<T, EmailError> {
+        let mut data = self.data.lock().await;
+        let mut updated = data.clone();
+        let result = change(&mut updated)?;
+
+        let json =
+            serde_json::to_vec_pretty(&updated).map_err(|e| EmailError::KeyStorage(e.into()))?;
+        write_atomic(&self.path, &json).await.map_err(|e| {
+            warn!("Failed to save {}: {}", self.path.display(), e);
+            EmailError::KeyStorage(e)
+        })?;
+        api_keys
+            .set_managed(&updated)
+            .map_err(|e| EmailError::KeyStorage(io::Error::other(e)))?;
+        *data = updated;
+        Ok(result)
+    }
+}
+
+// 找到运行时创建的 key，app_config.json 中的 key 只能停用和启用
+fn find_key<'a>(
+    api_keys: &ApiKeys,
+    keys: &'a mut [ManagedKey],
+    name: &str,
+) -> Result<&'a mut ManagedKey, EmailError> {
+    if api_keys.is_configured(name) {
+        warn!("API key {} is defined in app_config.json", name);
+        return Err(EmailError::ApiKeyReadOnly(name.to_string()));
+    }
+    keys.iter_mut()
+        .find(|key| key.name == name)
+        .ok_or_else(|| EmailError::ApiKeyNotFound(name.to_string()))
+}
+
+// key 列表中的一项，不包含 key 和哈希
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    name: String,
+    source: &'static str, // config 或 runtime
+    auth: AuthMethod,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_prefix: Option<String>,
+    scopes: Vec<Scope>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accounts: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_from: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_recipient_domains: Option<Vec<String>>,
+    rate_limit: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    disabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotated_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")] // 轮换前的 key 失效的时间
+    previous_key_expires_at: Option<DateTime<Utc>>,
+}
+
+impl From<&ApiKeyConfig> for ApiKeyInfo {
+    fn from(config: &ApiKeyConfig) -> Self {
+        ApiKeyInfo {
+            name: config.name.clone(),
+            source: "config",
+            auth: config.auth,
+            key_prefix: config.key_prefix.clone(),
+            scopes: config.scopes.clone(),
+            accounts: config.accounts.clone(),
+            allowed_from: config.allowed_from.clone(),
+            allowed_recipient_domains: config.allowed_recipient_domains.clone(),
+            rate_limit: config.rate_limit,
+            expires_at: config.expires_at,
+            disabled: false,
+            created_at: None,
+            rotated_at: None,
+            previous_key_expires_at: None,
+        }
+    }
+}
+
+impl From<&ManagedKey> for ApiKeyInfo {
+    fn from(key: &ManagedKey) -> Self {
+        ApiKeyInfo {
+            name: key.name.clone(),
+            source: "runtime",
+            auth: AuthMethod::ApiKey,
+            key_prefix: Some(key.key_prefix.clone()),
+            scopes: key.scopes.clone(),
+            accounts: key.accounts.clone(),
+            allowed_from: key.allowed_from.clone(),
+            allowed_recipient_domains: key.allowed_recipient_domains.clone(),
+            rate_limit: key.rate_limit,
+            expires_at: key.expires_at,
+            disabled: key.disabled,
+            created_at: Some(key.created_at),
+            rotated_at: key.rotated_at,
+            previous_key_expires_at: key
+                .previous_key
+                .as_ref()
+                .map(|previous| previous.expires_at)
+                .filter(|expires_at| *expires_at > Utc::now()),
+        }
+    }
+}
+
+// 新建或轮换后返回的 key，只返回这一次
+#[derive(Serialize)]
+pub struct IssuedApiKey {
+    key: String,
+    #[serde(flatten)]
+    info: ApiKeyInfo,
+}
+
+// 新建 key 的请求
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    accounts: Option<Vec<String>>,
+    #[serde(default)]
+    allowed_from: Option<Vec<String>>,
+    #[serde(default)]
+    allowed_recipient_domains: Option<Vec<String>>,
+    #[serde(default = "default_rate_limit")]
+    rate_limit: u32,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// 轮换参数
+#[derive(Deserialize)]
+pub struct RotateParams {
+    #[serde(default)] // 旧 key 继续有效的秒数，未指定时使用 key_rotation_overlap
+    overlap: Option<u64>,
+}
+
+// 所有 key，app_config.json 中的 key 在前，均不包含 key 本身
+pub async fn list_api_keys(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+) -> Result<Json<Vec<ApiKeyInfo>>, EmailError> {
+    client.require(Scope::Admin)?;
+
+    let data = state.key_store.get().await;
+    let mut keys = state
+        .api_keys
+        .configured()
+        .iter()
+        .map(|config| ApiKeyInfo {
+            disabled: data.disabled_config_keys.contains(&config.name),
+            ..ApiKeyInfo::from(config)
+        })
+        .collect::<Vec<_>>();
+    keys.extend(data.keys.iter().map(ApiKeyInfo::from));
+    Ok(Json(keys))
+}
+
+// 新建 key，名称已存在时返回 409
+pub async fn create_api_key(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Json(req): Json<CreateApiKeyRequest>,
+) -> Result<impl IntoResponse, EmailError> {
+    client.require(Scope::Admin)?;
+
+    let mut errors = Vec::new();
+    if req.name.is_empty()
+        || req.name.len() > MAX_NAME_LENGTH
+        || !req
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        errors.push(EmailError::InvalidField {
+            field: "name".to_string(),
+            reason: format!(
+                "must be 1 to {} letters, digits, '.', '-' or '_'",
+                MAX_NAME_LENGTH
+            ),
+        });
+    }
+    if req.scopes.is_empty() {
+        errors.push(EmailError::InvalidField {
+            field: "scopes".to_string(),
+            reason: "at least one scope is required".to_string(),
+        });
+    }
+    if req.rate_limit == 0 {
+        errors.push(EmailError::InvalidField {
+            field: "rate_limit".to_string(),
+            reason: "must be at least 1".to_string(),
+        });
+    }
+    if !errors.is_empty() {
+        return Err(EmailError::from_field_errors(errors));
+    }
+
+    let (key, key_prefix) = generate_key();
+    let managed = ManagedKey {
+        name: req.name,
+        key_hash: key_hash(&key),
+        key_prefix,
+        previous_key: None,
+        scopes: req.scopes,
+        accounts: req.accounts,
+        allowed_from: req.allowed_from,
+        allowed_recipient_domains: req.allowed_recipient_domains,
+        rate_limit: req.rate_limit,
+        expires_at: req.expires_at,
+        disabled: false,
+        created_at: Utc::now(),
+        rotated_at: None,
+    };
+    let info = ApiKeyInfo::from(&managed);
+    state
+        .key_store
+        .update(&state.api_keys, |data| {
+            if state.api_keys.is_configured(&managed.name)
+                || data.keys.iter().any(|key| key.name == managed.name)
+            {
+                warn!("API key {} already exists", managed.name);
+                return Err(EmailError::ApiKeyExists(managed.name.clone()));
+            }
+            data.keys.push(managed);
+            Ok(())
+        })
+        .await?;
+    info!("API key {} created by {}", info.name, client.name);
+
+    Ok((StatusCode::CREATED, Json(IssuedApiKey { key, info })))
+}
+
+// 生成新的 key，旧 key 在过渡期内仍然有效
+pub async fn rotate_api_key(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+    Query(params): Query<RotateParams>,
+) -> Result<Json<IssuedApiKey>, EmailError> {
+    client.require(Scope::Admin)?;
+
+    let overlap = params
+        .overlap
+        .unwrap_or(state.app_config.server.key_rotation_overlap);
+    if overlap > MAX_ROTATION_OVERLAP {
+        return Err(EmailError::InvalidField {
+            field: "overlap".to_string(),
+            reason: format!("must be at most {} seconds", MAX_ROTATION_OVERLAP),
+        });
+    }
+    let (key, key_prefix) = generate_key();
+    let key_hash = key_hash(&key);
+    let info = state
+        .key_store
+        .update(&state.api_keys, |data| {
+            let managed = find_key(&state.api_keys, &mut data.keys, &name)?;
+            let now = Utc::now();
+            let previous = PreviousKey {
+                key_prefix: std::mem::replace(&mut managed.key_prefix, key_prefix),
+                key_hash: std::mem::replace(&mut managed.key_hash, key_hash),
+                expires_at: now + chrono::Duration::seconds(overlap as i64), // 已限制在 30 天内
+            };
+            // 过渡期为 0 时旧 key 立即失效，再次轮换时更早的 key 也会失效
+            managed.previous_key = (overlap > 0).then_some(previous);
+            managed.rotated_at = Some(now);
+            Ok(ApiKeyInfo::from(&*managed))
+        })
+        .await?;
+    info!(
+        "API key {} rotated by {}, the previous key stays valid for {}s",
+        name, client.name, overlap
+    );
+
+    Ok(Json(IssuedApiKey { key, info }))
+}
+
+// 停用 key，停用后的 key（包括轮换前的 key）不能再通过认证
+pub async fn disable_api_key(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiKeyInfo>, EmailError> {
+    client.require(Scope::Admin)?;
+
+    let info = set_disabled(&state, &name, true).await?;
+    info!("API key {} disabled by {}", name, client.name);
+    Ok(Json(info))
+}
+
+// 重新启用停用的 key
+pub async fn enable_api_key(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiKeyInfo>, EmailError> {
+    client.require(Scope::Admin)?;
+
+    let info = set_disabled(&state, &name, false).await?;
+    info!("API key {} enabled by {}", name, client.name);
+    Ok(Json(info))
+}
+
+// app_config.json 中的 key 的停用状态保存在存储文件中，不修改配置文件
+async fn set_disabled(
+    state: &AppState,
+    name: &str,
+    disabled: bool,
+) -> Result<ApiKeyInfo, EmailError> {
+    state
+        .key_store
+        .update(&state.api_keys, |data| {
+            if let Some(config) = state
+                .api_keys
+                .configured()
+                .iter()
+                .find(|config| config.name == name)
+            {
+                data.disabled_config_keys
+                    .retain(|disabled| disabled != name);
+                if disabled {
+                    data.disabled_config_keys.push(name.to_string());
+                }
+                return Ok(ApiKeyInfo {
+                    disabled,
+                    ..ApiKeyInfo::from(config)
+                });
+            }
+            let managed = find_key(&state.api_keys, &mut data.keys, name)?;
+            managed.disabled = disabled;
+            Ok(ApiKeyInfo::from(&*managed))
+        })
+        .await
+}
+
+// 删除 key
+pub async fn delete_api_key(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiResponse>, EmailError> {
+    client.require(Scope::Admin)?;
+
+    state
+        .key_store
+        .update(&state.api_keys, |data| {
+            find_key(&state.api_keys, &mut data.keys, &name)?;
+            data.keys.retain(|key| key.name != name);
+            Ok(())
+        })
+        .await?;
+    info!("API key {} deleted by {}", name, client.name);
+
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("API key {} deleted", name),
+        ..Default::default()
+    }))
+}
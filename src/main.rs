@@ -1,37 +1,133 @@
+mod auth;
+mod batch;
+mod dkim;
+mod idempotency;
+mod jwt;
+mod keys;
+mod markdown;
+mod oauth2;
+mod queue;
+mod relay;
+mod templates;
+mod tls;
+mod transport;
+
+use auth::{ApiClient, ApiKeyConfig, ApiKeys, Scope};
 use axum::{
-    extract::{Json, State},
-    http::{HeaderMap, StatusCode},
+    extract::{DefaultBodyLimit, Json, Multipart, Path, Query, State},
+    http::StatusCode,
+    middleware,
     response::{IntoResponse, Response},
-    routing::post,
-    Router,
+    routing::{delete, get, post},
+    Extension, Router,
 };
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
 use config::{Config, File};
+use dkim::{DkimSettings, DkimSigner};
+use idempotency::IdempotencyStore;
+use jwt::JwtConfig;
+use keys::KeyStore;
 use lettre::{
+    message::{header::ContentType, Attachment, Mailbox, Mailboxes, MultiPart, SinglePart},
     transport::smtp::{
-        authentication::Credentials,
-        client::{Tls, TlsParameters},
-        Error as SmtpError,
+        authentication::{Credentials, Mechanism},
+        client::Tls,
+        PoolConfig,
     },
-    Message, SmtpTransport, Transport,
-};
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    Address, AsyncSmtpTransport, Message, Tokio1Executor,
 };
+use oauth2::OAuth2Config;
+use queue::{CancelError, MessageFilter, MessageRecord, MessageStatus, Queue, QueueConfig};
+use relay::RelayConfig;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use templates::{TemplateConfig, TemplateStore};
+use tls::TlsSettings;
 use tower_http::trace::TraceLayer;
 use tracing::{debug, error, info, warn};
+use transport::{Transport, TransportKind};
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Clone)]
 struct EmailConfig {
+    #[serde(default)] // 投递方式：smtp、sendmail、file 或 stub
+    transport: TransportKind,
+    #[serde(default = "default_sendmail_command")] // transport 为 sendmail 时调用的程序
+    sendmail_command: String,
+    #[serde(default = "default_file_dir")] // transport 为 file 时写入 .eml 文件的目录
+    file_dir: String,
+    #[serde(default)] // 配置了 relays 时可省略
     smtp_server: String,
+    #[serde(default = "default_smtp_port")]
     smtp_port: u16,
+    #[serde(default)] // 不使用 SMTP 投递时可省略
     email_account: String,
+    #[serde(default)] // 使用 OAuth2 时可省略
     email_password: String,
+    #[serde(default)] // OAuth2 配置，配置后使用 XOAUTH2 认证代替密码
+    oauth2: Option<OAuth2Config>,
     email_from: String,
-    email_to: String,
+    #[serde(deserialize_with = "one_or_many")] // 可以是单个地址或地址数组
+    email_to: Vec<String>,
     sender_name: String,
+    #[serde(default = "default_smtp_pool_max_size")] // 连接池最大连接数
+    smtp_pool_max_size: u32,
+    #[serde(default = "default_smtp_pool_idle_timeout")] // 空闲连接超时（秒）
+    smtp_pool_idle_timeout: u64,
+    #[serde(default = "default_smtp_send_timeout")] // 单次发送超时（秒）
+    smtp_send_timeout: u64,
+    #[serde(default)] // TLS 配置，未配置时按端口选择连接方式
+    tls: TlsSettings,
+    #[serde(default)] // DKIM 签名配置，按发件人域名选择
+    dkim: Vec<DkimSettings>,
+    #[serde(default)] // 中继列表，配置后代替 smtp_server 和 smtp_port
+    relays: Vec<RelayConfig>,
+    #[serde(default = "default_relay_failure_threshold")] // 中继连续失败多少次后熔断
+    relay_failure_threshold: u32,
+    #[serde(default = "default_relay_cooldown")] // 熔断持续时间（秒）
+    relay_cooldown: u64,
+}
+
+// 默认 sendmail 程序
+fn default_sendmail_command() -> String {
+    "sendmail".to_string()
+}
+
+// 默认 .eml 文件目录
+fn default_file_dir() -> String {
+    "outbox".to_string()
+}
+
+// 默认 SMTP 端口
+fn default_smtp_port() -> u16 {
+    587
+}
+
+// 默认熔断阈值
+fn default_relay_failure_threshold() -> u32 {
+    3
+}
+
+// 默认熔断时间：60 秒
+fn default_relay_cooldown() -> u64 {
+    60
+}
+
+// 默认连接池大小
+fn default_smtp_pool_max_size() -> u32 {
+    10
+}
+
+// 默认空闲连接超时：60 秒
+fn default_smtp_pool_idle_timeout() -> u64 {
+    60
+}
+
+// 默认单次发送超时：30 秒
+fn default_smtp_send_timeout() -> u64 {
+    30
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -40,7 +136,28 @@ struct ServerConfig {
     server_host: String,
     #[serde(default = "default_server_port")] // 如果未配置，使用默认端口
     server_port: u16,
-    api_key: String,
+    #[serde(default)] // 单个 API key，拥有所有权限，兼容旧配置
+    api_key: Option<String>,
+    #[serde(default)] // 多个 API key，每个 key 有自己的权限和限制
+    api_keys: Vec<ApiKeyConfig>,
+    #[serde(default = "default_max_attachment_size")] // 单个附件大小上限（字节）
+    max_attachment_size: usize,
+    #[serde(default = "default_max_total_attachment_size")] // 附件总大小上限（字节）
+    max_total_attachment_size: usize,
+    #[serde(default = "default_idempotency_window")] // 幂等键保留时间（秒）
+    idempotency_window: u64,
+    #[serde(default = "default_max_batch_size")] // 批量发送的最大邮件数
+    max_batch_size: usize,
+    #[serde(default)] // api_key 可以使用的账户，未配置时可以使用所有账户
+    allowed_accounts: Option<Vec<String>>,
+    #[serde(default = "default_signature_max_skew")] // 签名请求允许的时间偏差（秒）
+    signature_max_skew: u64,
+    #[serde(default)] // JWT 认证配置，可选
+    jwt: Option<JwtConfig>,
+    #[serde(default = "default_key_store")] // 运行时创建的 API key 的存储文件
+    key_store: String,
+    #[serde(default = "default_key_rotation_overlap")] // 轮换后旧 key 继续有效的时间（秒）
+    key_rotation_overlap: u64,
 }
 
 // 默认主机函数
@@ -53,267 +170,1164 @@ fn default_server_port() -> u16 {
     3000
 }
 
-// 整合两个配置的结构体
-#[derive(Debug, Deserialize, Clone)]
-struct AppConfig {
-    email: EmailConfig,
-    server: ServerConfig,
+// 默认单个附件大小上限：10 MiB
+fn default_max_attachment_size() -> usize {
+    10 * 1024 * 1024
 }
 
-// 请求频率限制结构
-struct RateLimit {
-    requests: HashMap<String, Vec<SystemTime>>,
+// 默认附件总大小上限：25 MiB
+fn default_max_total_attachment_size() -> usize {
+    25 * 1024 * 1024
 }
 
-impl RateLimit {
-    fn new() -> Self {
-        RateLimit {
-            requests: HashMap::new(),
-        }
-    }
+// 默认幂等键保留时间：24 小时
+fn default_idempotency_window() -> u64 {
+    24 * 3600
+}
 
-    fn is_allowed(&mut self, ip: &str) -> bool {
-        let now = SystemTime::now();
-        let requests = self.requests.entry(ip.to_string()).or_insert(Vec::new());
+// 默认签名时间偏差：5 分钟
+fn default_signature_max_skew() -> u64 {
+    300
+}
 
-        requests.retain(|&time| {
-            now.duration_since(time).unwrap_or(Duration::from_secs(0)) < Duration::from_secs(60)
-        });
+// 默认 key 存储文件
+fn default_key_store() -> String {
+    "api_keys.json".to_string()
+}
 
-        if requests.len() >= 10 {
-            warn!("Rate limit exceeded for IP: {}", ip);
-            return false;
-        }
+// 默认轮换过渡期：24 小时
+fn default_key_rotation_overlap() -> u64 {
+    24 * 3600
+}
 
-        requests.push(now);
-        debug!("Request allowed for IP: {} (count: {})", ip, requests.len());
-        true
-    }
+// 默认批量发送上限
+fn default_max_batch_size() -> usize {
+    500
+}
+
+// 请求体上限，需容纳 base64 编码后的附件
+fn request_body_limit(server_config: &ServerConfig) -> usize {
+    server_config.max_total_attachment_size / 3 * 4 + 1024 * 1024
+}
+
+// 整合所有配置的结构体
+#[derive(Debug, Deserialize, Clone)]
+struct AppConfig {
+    email: EmailConfig,
+    #[serde(default)] // 其他命名的 SMTP 账户，通过请求中的 account 选择
+    accounts: HashMap<String, EmailConfig>,
+    server: ServerConfig,
+    #[serde(default)] // 发送队列配置，可选
+    queue: QueueConfig,
+    #[serde(default)] // 邮件模板配置，可选
+    templates: TemplateConfig,
 }
 
 // 实现错误响应转换
 impl IntoResponse for EmailError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            EmailError::SmtpError(ref e) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to send email: {}", e),
-            ),
-            EmailError::RateLimit => (
-                StatusCode::TOO_MANY_REQUESTS,
-                "Rate limit exceeded".to_string(),
-            ),
-            EmailError::InvalidApiKey => (StatusCode::UNAUTHORIZED, "Invalid API key".to_string()),
-            EmailError::MissingApiKey => (StatusCode::UNAUTHORIZED, "Missing API key".to_string()),
-        };
-
+        let (status, error_message) = self.status_and_message();
         let body = Json(ApiResponse {
             status: "error".to_string(),
             message: error_message,
+            errors: self.field_errors(),
+            ..Default::default()
         });
 
         (status, body).into_response()
     }
 }
 
-// 验证 API key
-fn validate_api_key(headers: &HeaderMap, config_api_key: &str) -> Result<(), EmailError> {
-    debug!("Checking for API key in headers...");
-    let request_api_key = headers
-        .get("X-API-Key")
+// 检查客户端的权限和频率限制
+fn authorize(state: &AppState, client: &ApiClient, scope: Scope) -> Result<(), EmailError> {
+    client.require(scope)?;
+    state.api_keys.check_rate_limit(client)
+}
+
+// 查询客户端可以看到的邮件，admin 以外的客户端只能看到自己发送的邮件
+fn find_message(
+    state: &AppState,
+    client: &ApiClient,
+    id: &str,
+) -> Result<MessageRecord, EmailError> {
+    state
+        .queue
+        .get(id)
+        .filter(|record| client.is_admin() || record.client.as_deref() == Some(&client.name))
         .ok_or_else(|| {
-            warn!("No API key provided in request");
-            EmailError::MissingApiKey
-        })?
-        .to_str()
-        .map_err(|e| {
-            error!("Invalid API key format: {}", e);
-            EmailError::InvalidApiKey
-        })?;
+            debug!("Message {} not found for client {}", id, client.name);
+            EmailError::MessageNotFound(id.to_string())
+        })
+}
 
-    if request_api_key != config_api_key {
-        warn!("Invalid API key provided");
-        return Err(EmailError::InvalidApiKey);
+// 查询单封邮件状态
+async fn get_message(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(id): Path<String>,
+) -> Result<Json<MessageRecord>, EmailError> {
+    client.require(Scope::ReadStatus)?;
+
+    find_message(&state, &client, &id).map(Json)
+}
+
+// 查询邮件状态列表，支持按状态和收件人筛选
+async fn list_messages(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Query(mut filter): Query<MessageFilter>,
+) -> Result<Json<Vec<MessageRecord>>, EmailError> {
+    client.require(Scope::ReadStatus)?;
+
+    if !client.is_admin() {
+        filter.client = Some(client.name.clone());
     }
+    Ok(Json(state.queue.list(&filter)))
+}
+
+// 取消尚未投递的邮件
+async fn cancel_message(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(id): Path<String>,
+) -> Result<Json<MessageRecord>, EmailError> {
+    client.require(Scope::Send)?;
 
-    debug!("API key validation successful");
-    Ok(())
+    find_message(&state, &client, &id)?;
+    match state.queue.cancel(&id).await {
+        Ok(record) => Ok(Json(record)),
+        Err(CancelError::NotFound) => Err(EmailError::MessageNotFound(id)),
+        Err(CancelError::NotCancellable(status)) => {
+            warn!("Message {} cannot be cancelled in status {:?}", id, status);
+            Err(EmailError::MessageNotCancellable(id))
+        }
+    }
 }
 
 // 发送邮件处理函数
 async fn send_email(
     State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
+    Extension(client): Extension<Arc<ApiClient>>,
     Json(req): Json<EmailRequest>,
 ) -> Result<impl IntoResponse, EmailError> {
-    // 验证 API key
-    validate_api_key(&headers, &state.app_config.server.api_key)?;
-
-    // 获取客户端 IP
-    let ip = headers
-        .get("x-forwarded-for")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
-    debug!("Request from IP: {}", ip);
-
-    // 检查频率限制
-    let mut rate_limit = state.rate_limit.lock().unwrap();
-    if !rate_limit.is_allowed(&ip) {
-        return Err(EmailError::RateLimit);
-    }
-
-    // 使用请求中的值或配置中的默认值
-    let from = if req.from.is_empty() {
-        debug!("Using default from address");
-        &state.app_config.email.email_from
+    authorize(&state, &client, Scope::Send)?;
+
+    let mut attachments = AttachmentSet::new(&state.app_config.server);
+    for attachment in &req.attachments {
+        attachments.push_base64(attachment)?;
+    }
+
+    if req.dry_run {
+        let preview = preview_email(&state, &client, req, attachments.into_inner())?;
+        return Ok((StatusCode::OK, Json(preview)).into_response());
+    }
+    let response = enqueue_email(&state, &client, req, attachments.into_inner()).await?;
+    Ok((StatusCode::ACCEPTED, Json(response)).into_response())
+}
+
+// 通过 multipart/form-data 上传附件并发送邮件
+// `request` 字段为与 /send-email 相同的 JSON，其余带文件名的字段作为附件
+async fn send_email_multipart(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, EmailError> {
+    authorize(&state, &client, Scope::Send)?;
+
+    let mut req: Option<EmailRequest> = None;
+    let mut attachments = AttachmentSet::new(&state.app_config.server);
+
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| {
+        warn!("Failed to read multipart field: {}", e);
+        EmailError::InvalidRequest(e.body_text())
+    })? {
+        let name = field.name().unwrap_or_default().to_string();
+        match field.file_name().map(str::to_string) {
+            Some(filename) => {
+                let content_type = field
+                    .content_type()
+                    .unwrap_or("application/octet-stream")
+                    .to_string();
+                debug!("Receiving attachment: {} ({})", filename, content_type);
+
+                // 分块读取，超出限制时立即中止
+                let mut content = Vec::new();
+                while let Some(chunk) = field.chunk().await.map_err(|e| {
+                    warn!("Failed to read attachment {}: {}", filename, e);
+                    EmailError::InvalidRequest(e.body_text())
+                })? {
+                    attachments.check_size(&filename, content.len() + chunk.len())?;
+                    content.extend_from_slice(&chunk);
+                }
+                attachments.push(filename, &content_type, content)?;
+            }
+            None if name == "request" => {
+                let text = field
+                    .text()
+                    .await
+                    .map_err(|e| EmailError::InvalidRequest(e.body_text()))?;
+                req = Some(serde_json::from_str(&text).map_err(|e| {
+                    warn!("Invalid request field: {}", e);
+                    EmailError::InvalidRequest(format!("Invalid request field: {}", e))
+                })?);
+            }
+            None => {
+                debug!("Ignoring unknown multipart field: {}", name);
+            }
+        }
+    }
+
+    let req = req.ok_or_else(|| {
+        warn!("Multipart request has no request field");
+        EmailError::InvalidRequest("Missing request field".to_string())
+    })?;
+    for attachment in &req.attachments {
+        attachments.push_base64(attachment)?;
+    }
+
+    if req.dry_run {
+        let preview = preview_email(&state, &client, req, attachments.into_inner())?;
+        return Ok((StatusCode::OK, Json(preview)).into_response());
+    }
+    let response = enqueue_email(&state, &client, req, attachments.into_inner()).await?;
+    Ok((StatusCode::ACCEPTED, Json(response)).into_response())
+}
+
+// 构建邮件并写入发送队列
+async fn enqueue_email(
+    state: &AppState,
+    client: &ApiClient,
+    req: EmailRequest,
+    attachments: Vec<SinglePart>,
+) -> Result<ApiResponse, EmailError> {
+    let id = Uuid::new_v4().to_string();
+    let send_at = parse_send_at(req.send_at.as_deref())?;
+    let req = state.templates.apply(req)?;
+    let account = state.account(req.account.as_deref(), client)?.name.clone();
+    let email = build_message(state, client, &id, req, attachments, send_at)?;
+
+    // 写入队列，由后台任务负责投递
+    let record = state
+        .queue
+        .enqueue(
+            &id,
+            &account,
+            &client.name,
+            email.envelope(),
+            email.formatted(),
+            send_at,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to queue message {}: {}", id, e);
+            EmailError::Queue(e)
+        })?;
+
+    let message = match record.next_attempt_at {
+        Some(send_at) if record.status == MessageStatus::Scheduled => {
+            info!(
+                "Message {} from client {} scheduled at {} for {:?}",
+                id,
+                client.name,
+                send_at,
+                email.envelope().to()
+            );
+            format!("Email scheduled for delivery at {}", send_at.to_rfc3339())
+        }
+        _ => {
+            info!(
+                "Message {} from client {} queued for {:?}",
+                id,
+                client.name,
+                email.envelope().to()
+            );
+            "Email queued for delivery".to_string()
+        }
+    };
+
+    Ok(ApiResponse {
+        status: "success".to_string(),
+        message,
+        id: Some(id),
+        ..Default::default()
+    })
+}
+
+// 构建邮件但不写入队列，返回渲染结果和完整的 MIME 内容
+fn preview_email(
+    state: &AppState,
+    client: &ApiClient,
+    req: EmailRequest,
+    attachments: Vec<SinglePart>,
+) -> Result<MessagePreview, EmailError> {
+    let send_at = parse_send_at(req.send_at.as_deref())?;
+    let req = state.templates.apply(req)?;
+
+    let subject = req.subject.clone().unwrap_or_default();
+    let (text, html) = select_bodies(&req);
+    let text = text.or_else(|| html.as_deref().map(html_to_text));
+    let email = build_message(
+        state,
+        client,
+        &Uuid::new_v4().to_string(),
+        req,
+        attachments,
+        send_at,
+    )?;
+    debug!("Built preview for {:?}", email.envelope().to());
+
+    Ok(MessagePreview {
+        subject,
+        text,
+        html,
+        mime: String::from_utf8_lossy(&email.formatted()).into_owned(),
+    })
+}
+
+// 解析 RFC 3339 格式的定时发送时间
+fn parse_send_at(send_at: Option<&str>) -> Result<Option<DateTime<Utc>>, EmailError> {
+    let Some(send_at) = send_at.filter(|s| !s.is_empty()) else {
+        return Ok(None);
+    };
+    DateTime::parse_from_rfc3339(send_at)
+        .map(|send_at| Some(send_at.with_timezone(&Utc)))
+        .map_err(|e| {
+            warn!("Invalid send_at: {}", e);
+            EmailError::InvalidField {
+                field: "send_at".to_string(),
+                reason: format!("must be an RFC 3339 timestamp: {}", e),
+            }
+        })
+}
+
+// 校验请求并构建邮件
+fn build_message(
+    state: &AppState,
+    client: &ApiClient,
+    id: &str,
+    req: EmailRequest,
+    attachments: Vec<SinglePart>,
+    send_at: Option<DateTime<Utc>>,
+) -> Result<Message, EmailError> {
+    let mut errors = Vec::new();
+    let account = state.account(req.account.as_deref(), client)?;
+    let defaults = &account.config;
+
+    // 使用请求中的值或所选账户配置中的默认值
+    let (from_field, from) = if req.from.is_empty() {
+        debug!("Using default from address of account {}", account.name);
+        (account.config_field("email_from"), &defaults.email_from)
     } else {
         debug!("Using custom from address: {}", req.from);
-        &req.from
+        ("from".to_string(), &req.from)
     };
 
-    let to = if req.to.is_empty() {
-        debug!("Using default to address");
-        &state.app_config.email.email_to
+    // 仅当 to、cc、bcc 全部为空时才使用默认收件人
+    let (to_field, to) = if req.to.is_empty() && req.cc.is_empty() && req.bcc.is_empty() {
+        debug!("Using default to address of account {}", account.name);
+        (account.config_field("email_to"), &defaults.email_to)
     } else {
-        debug!("Using custom to address: {}", req.to);
-        &req.to
+        debug!("Using custom to address: {:?}", req.to);
+        ("to".to_string(), &req.to)
     };
 
-    info!("Preparing to send email from {} to {}", from, to);
+    info!(
+        "Preparing to send email from {} to {:?} (cc: {:?}, bcc: {:?})",
+        from, to, req.cc, req.bcc
+    );
 
     // 优先使用请求中的昵称，如果没有则使用配置中的昵称
-    let sender_name = if !req.sender_name.is_empty() {
+    let (sender_name_field, sender_name) = if !req.sender_name.is_empty() {
         debug!("Using custom sender name: {}", req.sender_name);
-        &req.sender_name
+        ("sender_name".to_string(), &req.sender_name)
     } else {
-        debug!(
-            "Using default sender name: {}",
-            state.app_config.email.sender_name
-        );
-        &state.app_config.email.sender_name
+        debug!("Using default sender name: {}", defaults.sender_name);
+        (account.config_field("sender_name"), &defaults.sender_name)
     };
 
-    // 构建发件人地址字符串，包含昵称
-    let from_addr = format!("{} <{}>", sender_name, from);
+    // 校验所有字段，收集全部错误后一起返回
+    check_header(&sender_name_field, sender_name, &mut errors);
+    let subject = req.subject.clone().unwrap_or_else(|| {
+        errors.push(EmailError::InvalidField {
+            field: "subject".to_string(),
+            reason: "is required unless template is set".to_string(),
+        });
+        String::new()
+    });
+    check_header("subject", &subject, &mut errors);
+    let from_address = from
+        .parse::<Address>()
+        .map_err(|e| errors.push(EmailError::invalid_address(&from_field, e)))
+        .ok();
+    let to = parse_mailboxes(&to_field, to, &mut errors);
+    let cc = parse_mailboxes("cc", &req.cc, &mut errors);
+    let bcc = parse_mailboxes("bcc", &req.bcc, &mut errors);
+    let reply_to = parse_mailboxes("reply_to", &req.reply_to, &mut errors);
+
+    // 根据请求中的正文字段决定邮件结构
+    let (text, html) = select_bodies(&req);
+    let body = match (text, html) {
+        (Some(text), Some(html)) => {
+            debug!("Building multipart/alternative body");
+            BodyPart::Multi(MultiPart::alternative_plain_html(text, html))
+        }
+        (None, Some(html)) => {
+            debug!("Building multipart/alternative body with generated text fallback");
+            BodyPart::Multi(MultiPart::alternative_plain_html(html_to_text(&html), html))
+        }
+        (Some(text), None) => BodyPart::Single(SinglePart::plain(text)),
+        (None, None) => {
+            warn!("Request has no body");
+            return Err(EmailError::MissingBody);
+        }
+    };
+
+    let Some(from_address) = from_address.filter(|_| errors.is_empty()) else {
+        return Err(EmailError::from_field_errors(errors));
+    };
+
+    // 检查发件人和收件人是否符合 API key 的限制
+    let recipients = [(to_field.as_str(), &to), ("cc", &cc), ("bcc", &bcc)]
+        .into_iter()
+        .flat_map(|(field, mailboxes)| {
+            mailboxes
+                .iter()
+                .enumerate()
+                .map(move |(index, mailbox)| (format!("{}[{}]", field, index), &mailbox.email))
+        });
+    client.check_addresses((&from_field, &from_address), recipients)?;
 
     // 构建邮件
-    debug!(
-        "Building email message with sender name: {}",
-        state.app_config.email.sender_name
-    );
-    let email = Message::builder()
-        .from(from_addr.parse().unwrap())
-        .to(to.parse().unwrap())
-        .subject(req.subject)
-        .body(req.body)
-        .unwrap();
+    debug!("Building email message with sender name: {}", sender_name);
+    // 使用消息 ID 生成 Message-ID 头，便于与队列记录对应
+    let from_domain = from_address.domain().to_string();
+    let message_id = format!("<{}@{}>", id, from_domain);
+    let mut builder = Message::builder()
+        .message_id(Some(message_id))
+        .from(Mailbox::new(Some(sender_name.clone()), from_address))
+        .subject(subject);
+    // 定时发送的邮件在入队时就已签名，Date 使用发送时间而不是入队时间
+    if let Some(send_at) = send_at.filter(|send_at| *send_at > Utc::now()) {
+        builder = builder.date(send_at.into());
+    }
+    for mailbox in to {
+        builder = builder.to(mailbox);
+    }
+    for mailbox in cc {
+        builder = builder.cc(mailbox);
+    }
+    for mailbox in bcc {
+        builder = builder.bcc(mailbox);
+    }
+    for mailbox in reply_to {
+        builder = builder.reply_to(mailbox);
+    }
+
+    // 有附件时使用 multipart/mixed 包裹正文和附件
+    let mut email = if attachments.is_empty() {
+        match body {
+            BodyPart::Single(part) => builder.singlepart(part),
+            BodyPart::Multi(part) => builder.multipart(part),
+        }
+    } else {
+        debug!(
+            "Building multipart/mixed message with {} attachment(s)",
+            attachments.len()
+        );
+        let mixed = match body {
+            BodyPart::Single(part) => MultiPart::mixed().singlepart(part),
+            BodyPart::Multi(part) => MultiPart::mixed().multipart(part),
+        };
+        builder.multipart(attachments.into_iter().fold(mixed, MultiPart::singlepart))
+    }
+    .map_err(|e| {
+        warn!("Failed to build email message: {}", e);
+        EmailError::MessageBuild(e)
+    })?;
     debug!("Email message built successfully");
 
-    // 发送邮件
-    info!("Sending email...");
-    match state.smtp_transport.send(&email) {
-        Ok(_) => {
-            info!("Email sent successfully to {}", to);
-            Ok(Json(ApiResponse {
-                status: "success".to_string(),
-                message: "Email sent successfully".to_string(),
-            }))
+    account.dkim.sign(&mut email, &from_domain);
+    Ok(email)
+}
+
+// 选择纯文本和 HTML 正文，text_body 优先于 body，空字符串视为未设置
+// markdown_body 渲染为 HTML，Markdown 原文作为纯文本
+fn select_bodies(req: &EmailRequest) -> (Option<String>, Option<String>) {
+    let non_empty = |s: Option<&String>| s.filter(|s| !s.is_empty()).cloned();
+    let markdown = non_empty(req.markdown_body.as_ref());
+    let text = non_empty(req.text_body.as_ref())
+        .or_else(|| non_empty(Some(&req.body)))
+        .or_else(|| markdown.clone());
+    let html = non_empty(req.html_body.as_ref()).or_else(|| {
+        markdown.as_deref().map(|markdown| {
+            debug!("Rendering markdown body");
+            markdown::to_html(markdown)
+        })
+    });
+    (text, html)
+}
+
+// 校验头部字段，不允许包含换行符
+fn check_header(field: &str, value: &str, errors: &mut Vec<EmailError>) {
+    if value.contains(['\r', '\n']) {
+        warn!("Header field {} contains line breaks", field);
+        errors.push(EmailError::InvalidHeader {
+            field: field.to_string(),
+            reason: "must not contain line breaks".to_string(),
+        });
+    }
+}
+
+// 解析地址列表，每一项可以包含多个以逗号分隔的地址
+fn parse_mailboxes(
+    field: &str,
+    addresses: &[String],
+    errors: &mut Vec<EmailError>,
+) -> Vec<Mailbox> {
+    let mut mailboxes = Vec::new();
+    for (index, address) in addresses.iter().enumerate() {
+        match address.parse::<Mailboxes>() {
+            Ok(parsed) => mailboxes.extend(parsed),
+            Err(e) => {
+                let field = if addresses.len() > 1 {
+                    format!("{}[{}]", field, index)
+                } else {
+                    field.to_string()
+                };
+                errors.push(EmailError::invalid_address(&field, e));
+            }
+        }
+    }
+    mailboxes
+}
+
+// 反序列化单个字符串或字符串数组，空字符串视为空列表
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(address) => vec![address],
+        OneOrMany::Many(addresses) => addresses,
+    }
+    .into_iter()
+    .filter(|address| !address.trim().is_empty())
+    .collect())
+}
+
+// 邮件正文部分
+enum BodyPart {
+    Single(SinglePart),
+    Multi(MultiPart),
+}
+
+// 附件集合，负责解码和大小限制
+struct AttachmentSet {
+    parts: Vec<SinglePart>,
+    total_size: usize,
+    max_attachment_size: usize,
+    max_total_size: usize,
+}
+
+impl AttachmentSet {
+    fn new(server_config: &ServerConfig) -> Self {
+        AttachmentSet {
+            parts: Vec::new(),
+            total_size: 0,
+            max_attachment_size: server_config.max_attachment_size,
+            max_total_size: server_config.max_total_attachment_size,
         }
-        Err(e) => {
-            error!("Failed to send email: {}", e);
-            Err(EmailError::SmtpError(e))
+    }
+
+    // 检查单个附件和附件总大小是否超出限制
+    fn check_size(&self, filename: &str, size: usize) -> Result<(), EmailError> {
+        if size > self.max_attachment_size {
+            warn!("Attachment {} exceeds size limit: {} bytes", filename, size);
+            return Err(EmailError::AttachmentTooLarge(format!(
+                "Attachment {} exceeds the limit of {} bytes",
+                filename, self.max_attachment_size
+            )));
         }
+        if self.total_size + size > self.max_total_size {
+            warn!("Total attachment size exceeds limit");
+            return Err(EmailError::AttachmentTooLarge(format!(
+                "Total attachment size exceeds the limit of {} bytes",
+                self.max_total_size
+            )));
+        }
+        Ok(())
+    }
+
+    // 解码 base64 附件
+    fn push_base64(&mut self, attachment: &AttachmentRequest) -> Result<(), EmailError> {
+        // 解码前先按编码长度估算，避免解码超大内容
+        self.check_size(&attachment.filename, attachment.content.len() / 4 * 3)?;
+        let content = BASE64_STANDARD
+            .decode(attachment.content.trim())
+            .map_err(|e| {
+                warn!(
+                    "Invalid base64 in attachment {}: {}",
+                    attachment.filename, e
+                );
+                EmailError::InvalidAttachment(format!(
+                    "Attachment {} is not valid base64: {}",
+                    attachment.filename, e
+                ))
+            })?;
+        let content_type = attachment
+            .content_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        self.push(attachment.filename.clone(), content_type, content)
+    }
+
+    fn push(
+        &mut self,
+        filename: String,
+        content_type: &str,
+        content: Vec<u8>,
+    ) -> Result<(), EmailError> {
+        self.check_size(&filename, content.len())?;
+        let content_type = ContentType::parse(content_type).map_err(|e| {
+            warn!("Invalid content type for attachment {}: {}", filename, e);
+            EmailError::InvalidAttachment(format!(
+                "Attachment {} has an invalid content type: {}",
+                filename, content_type
+            ))
+        })?;
+        debug!("Adding attachment: {} ({} bytes)", filename, content.len());
+        self.total_size += content.len();
+        self.parts
+            .push(Attachment::new(filename).body(content, content_type));
+        Ok(())
+    }
+
+    fn into_inner(self) -> Vec<SinglePart> {
+        self.parts
     }
 }
 
 // 应用状态
 struct AppState {
-    rate_limit: Mutex<RateLimit>,
-    smtp_transport: SmtpTransport,
+    api_keys: ApiKeys,
+    key_store: KeyStore,
+    idempotency: IdempotencyStore,
+    queue: Queue,
+    templates: TemplateStore,
+    accounts: HashMap<String, SmtpAccount>,
     app_config: AppConfig,
 }
 
+impl AppState {
+    // 查找请求使用的 SMTP 账户，未指定时使用默认账户
+    fn account(&self, name: Option<&str>, client: &ApiClient) -> Result<&SmtpAccount, EmailError> {
+        let name = name
+            .filter(|name| !name.is_empty())
+            .unwrap_or(DEFAULT_ACCOUNT);
+        let account = self.accounts.get(name).ok_or_else(|| {
+            warn!("Unknown account: {}", name);
+            EmailError::InvalidField {
+                field: "account".to_string(),
+                reason: format!("unknown account: {}", name),
+            }
+        })?;
+
+        client.check_account(name)?;
+        Ok(account)
+    }
+}
+
+// 默认账户名称，对应配置中的 email
+const DEFAULT_ACCOUNT: &str = "default";
+
+// SMTP 账户，包含配置、投递传输和 DKIM 签名器
+struct SmtpAccount {
+    name: String,
+    config: EmailConfig,
+    transport: Transport,
+    dkim: DkimSigner,
+}
+
+impl SmtpAccount {
+    fn new(name: &str, config: &EmailConfig) -> Self {
+        let transport = Transport::new(name, config).unwrap_or_else(|e| {
+            error!("Failed to configure transport for account {}: {}", name, e);
+            std::process::exit(1);
+        });
+        let dkim = DkimSigner::load(&config.dkim).unwrap_or_else(|e| {
+            error!(
+                "Failed to load DKIM configuration for account {}: {}",
+                name, e
+            );
+            std::process::exit(1);
+        });
+
+        SmtpAccount {
+            name: name.to_string(),
+            config: config.clone(),
+            transport,
+            dkim,
+        }
+    }
+
+    // 配置项在配置文件中的路径，用于错误提示
+    fn config_field(&self, field: &str) -> String {
+        if self.name == DEFAULT_ACCOUNT {
+            format!("email.{}", field)
+        } else {
+            format!("accounts.{}.{}", self.name, field)
+        }
+    }
+}
+
 // 邮件请求结构
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 struct EmailRequest {
     #[serde(default)] // 使字段成为可选
     from: String,
-    #[serde(default)] // 使字段成为可选
-    to: String,
+    #[serde(default, deserialize_with = "one_or_many")] // 单个地址或地址数组
+    to: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")] // 抄送
+    cc: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")] // 密送，不会出现在邮件头中
+    bcc: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")] // 回复地址
+    reply_to: Vec<String>,
     #[serde(default)] // 使字段可选
     sender_name: String, // 添加发件人昵称字段
-    subject: String,
+    #[serde(default)] // 使用模板时可省略
+    subject: Option<String>,
+    #[serde(default)] // 纯文本正文，兼容旧版本
     body: String,
+    #[serde(default)] // 纯文本正文，优先于 body
+    text_body: Option<String>,
+    #[serde(default)] // HTML 正文，与纯文本一起组成 multipart/alternative
+    html_body: Option<String>,
+    #[serde(default)] // Markdown 正文，渲染为 HTML，原文作为纯文本
+    markdown_body: Option<String>,
+    #[serde(default)] // SMTP 账户名称，为空时使用默认账户
+    account: Option<String>,
+    #[serde(default)] // 服务端模板名称，用于生成主题和正文
+    template: Option<String>,
+    #[serde(default)] // 模板语言，例如 zh-CN，没有对应模板时按语言回退
+    locale: Option<String>,
+    #[serde(default)] // 模板变量
+    variables: Map<String, Value>,
+    #[serde(default)] // 附件列表
+    attachments: Vec<AttachmentRequest>,
+    #[serde(default)] // 定时发送时间，RFC 3339 格式
+    send_at: Option<String>,
+    #[serde(default)] // 只构建邮件并返回预览，不发送
+    dry_run: bool,
+}
+
+// 附件请求结构
+#[derive(Deserialize, Clone)]
+struct AttachmentRequest {
+    filename: String,
+    #[serde(default)] // 未指定时使用 application/octet-stream
+    content_type: Option<String>,
+    content: String, // base64 编码的文件内容
 }
 
 // API 响应结构
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 struct ApiResponse {
     status: String,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")] // 消息 ID，邮件入队后返回
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")] // 字段级错误，仅在校验失败时返回
+    errors: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Vec::is_empty")] // 批量发送时每一项的结果
+    results: Vec<ApiResponse>,
+}
+
+// 邮件预览，用于 dry_run 和模板渲染
+#[derive(Serialize)]
+struct MessagePreview {
+    subject: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    html: Option<String>,
+    mime: String, // 完整的 MIME 邮件内容
+}
+
+// 字段级错误
+#[derive(Serialize, Debug)]
+struct FieldError {
+    field: String,
+    reason: String,
 }
 
 // 自定义错误类型
 #[derive(thiserror::Error, Debug)]
 enum EmailError {
-    #[error("SMTP error: {0}")]
-    SmtpError(#[from] lettre::transport::smtp::Error),
+    #[error("Queue error: {0}")]
+    Queue(std::io::Error),
+    #[error("Message not found: {0}")]
+    MessageNotFound(String),
+    #[error("Message cannot be cancelled: {0}")]
+    MessageNotCancellable(String),
+    #[error("Template not found: {0}")]
+    TemplateNotFound(String),
+    #[error("Template already exists: {0}")]
+    TemplateExists(String),
+    #[error("Template storage error: {0}")]
+    TemplateStorage(std::io::Error),
+    #[error("Failed to render template {field}: {reason}")]
+    TemplateRender { field: String, reason: String },
+    #[error("Invalid field {field}: {reason}")]
+    InvalidField { field: String, reason: String },
+    #[error("Idempotency-Key reused with a different request body")]
+    IdempotencyConflict,
+    #[error("Request with the same Idempotency-Key is in progress")]
+    IdempotencyInProgress,
     #[error("Rate limit exceeded")]
     RateLimit,
+    #[error("Account not allowed: {0}")]
+    AccountNotAllowed(String),
+    #[error("Scope not allowed: {0}")]
+    ScopeNotAllowed(Scope),
+    #[error("Address not allowed")]
+    AddressNotAllowed(Vec<FieldError>),
     #[error("Invalid API key")]
     InvalidApiKey,
+    #[error("API key expired")]
+    ApiKeyExpired,
+    #[error("API key disabled")]
+    ApiKeyDisabled,
+    #[error("API key not found: {0}")]
+    ApiKeyNotFound(String),
+    #[error("API key already exists: {0}")]
+    ApiKeyExists(String),
+    #[error("API key is read-only: {0}")]
+    ApiKeyReadOnly(String),
+    #[error("Failed to store API keys: {0}")]
+    KeyStorage(std::io::Error),
     #[error("Missing API key")]
     MissingApiKey,
+    #[error("Invalid request signature: {0}")]
+    InvalidSignature(String),
+    #[error("Invalid bearer token: {0}")]
+    InvalidToken(String),
+    #[error("Bearer token expired")]
+    TokenExpired,
+    #[error("Bearer token audience not accepted")]
+    TokenAudience,
+    #[error("Missing email body")]
+    MissingBody,
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Invalid attachment: {0}")]
+    InvalidAttachment(String),
+    #[error("Attachment too large: {0}")]
+    AttachmentTooLarge(String),
+    #[error("Invalid address in {field}: {reason}")]
+    InvalidAddress { field: String, reason: String },
+    #[error("Invalid header {field}: {reason}")]
+    InvalidHeader { field: String, reason: String },
+    #[error("Request validation failed")]
+    Validation(Vec<FieldError>),
+    #[error("Failed to build message: {0}")]
+    MessageBuild(lettre::error::Error),
+}
+
+impl EmailError {
+    // 错误对应的 HTTP 状态码和提示信息
+    fn status_and_message(&self) -> (StatusCode, String) {
+        match self {
+            EmailError::Queue(ref e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to queue email: {}", e),
+            ),
+            EmailError::MessageNotFound(ref id) => {
+                (StatusCode::NOT_FOUND, format!("Message {} not found", id))
+            }
+            EmailError::MessageNotCancellable(ref id) => (
+                StatusCode::CONFLICT,
+                format!("Message {} has already been sent or cancelled", id),
+            ),
+            EmailError::TemplateNotFound(ref name) => (
+                StatusCode::NOT_FOUND,
+                format!("Template {} not found", name),
+            ),
+            EmailError::TemplateExists(ref name) => (
+                StatusCode::CONFLICT,
+                format!("Template {} already exists", name),
+            ),
+            EmailError::TemplateStorage(ref e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store template: {}", e),
+            ),
+            EmailError::TemplateRender { .. } => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Failed to render template".to_string(),
+            ),
+            EmailError::IdempotencyConflict => (
+                StatusCode::CONFLICT,
+                "Idempotency-Key was already used with a different request body".to_string(),
+            ),
+            EmailError::IdempotencyInProgress => (
+                StatusCode::CONFLICT,
+                "A request with the same Idempotency-Key is still in progress".to_string(),
+            ),
+            EmailError::RateLimit => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Rate limit exceeded".to_string(),
+            ),
+            EmailError::AccountNotAllowed(ref name) => (
+                StatusCode::FORBIDDEN,
+                format!("Account {} is not allowed for this API key", name),
+            ),
+            EmailError::ScopeNotAllowed(scope) => (
+                StatusCode::FORBIDDEN,
+                format!("API key does not have the {} scope", scope),
+            ),
+            EmailError::AddressNotAllowed(_) => (
+                StatusCode::FORBIDDEN,
+                "Address not allowed for this API key".to_string(),
+            ),
+            EmailError::InvalidApiKey => (StatusCode::UNAUTHORIZED, "Invalid API key".to_string()),
+            EmailError::ApiKeyExpired => {
+                (StatusCode::UNAUTHORIZED, "API key has expired".to_string())
+            }
+            EmailError::ApiKeyDisabled => (
+                StatusCode::UNAUTHORIZED,
+                "API key has been disabled".to_string(),
+            ),
+            EmailError::ApiKeyNotFound(ref name) => {
+                (StatusCode::NOT_FOUND, format!("API key {} not found", name))
+            }
+            EmailError::ApiKeyExists(ref name) => (
+                StatusCode::CONFLICT,
+                format!("API key {} already exists", name),
+            ),
+            EmailError::ApiKeyReadOnly(ref name) => (
+                StatusCode::CONFLICT,
+                format!(
+                    "API key {} is defined in app_config.json and can only be disabled or enabled at runtime",
+                    name
+                ),
+            ),
+            EmailError::KeyStorage(ref e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store API keys: {}", e),
+            ),
+            EmailError::MissingApiKey => (StatusCode::UNAUTHORIZED, "Missing API key".to_string()),
+            EmailError::InvalidSignature(reason) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Invalid request signature: {}", reason),
+            ),
+            EmailError::InvalidToken(reason) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Invalid bearer token: {}", reason),
+            ),
+            EmailError::TokenExpired => (
+                StatusCode::UNAUTHORIZED,
+                "Bearer token has expired".to_string(),
+            ),
+            EmailError::TokenAudience => (
+                StatusCode::UNAUTHORIZED,
+                "Bearer token is not intended for this server".to_string(),
+            ),
+            EmailError::MissingBody => (
+                StatusCode::BAD_REQUEST,
+                "One of body, text_body, html_body or markdown_body is required".to_string(),
+            ),
+            EmailError::InvalidRequest(message) | EmailError::InvalidAttachment(message) => {
+                (StatusCode::BAD_REQUEST, message.clone())
+            }
+            EmailError::AttachmentTooLarge(message) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, message.clone())
+            }
+            EmailError::InvalidAddress { .. }
+            | EmailError::InvalidHeader { .. }
+            | EmailError::InvalidField { .. }
+            | EmailError::Validation(_) => (
+                StatusCode::BAD_REQUEST,
+                "Request validation failed".to_string(),
+            ),
+            EmailError::MessageBuild(ref e) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Failed to build message: {}", e),
+            ),
+        }
+    }
+
+    fn invalid_address(field: &str, reason: impl std::fmt::Display) -> Self {
+        EmailError::InvalidAddress {
+            field: field.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    // 合并多个字段错误，只有一个时保留原始错误类型
+    fn from_field_errors(mut errors: Vec<EmailError>) -> Self {
+        if errors.len() == 1 {
+            return errors.remove(0);
+        }
+        EmailError::Validation(errors.iter().flat_map(EmailError::field_errors).collect())
+    }
+
+    // 转换为字段级错误列表
+    fn field_errors(&self) -> Vec<FieldError> {
+        match self {
+            EmailError::InvalidAddress { field, reason }
+            | EmailError::InvalidHeader { field, reason }
+            | EmailError::InvalidField { field, reason }
+            | EmailError::TemplateRender { field, reason } => vec![FieldError {
+                field: field.clone(),
+                reason: reason.clone(),
+            }],
+            EmailError::Validation(errors) | EmailError::AddressNotAllowed(errors) => errors
+                .iter()
+                .map(|e| FieldError {
+                    field: e.field.clone(),
+                    reason: e.reason.clone(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// 从 HTML 生成纯文本备用正文
+fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = rest[start + 1..start + end].trim().to_ascii_lowercase();
+        rest = &rest[start + end + 1..];
+
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        match name {
+            // 跳过脚本和样式内容
+            "script" | "style" | "head" if !tag.starts_with('/') => {
+                let close = format!("</{}", name);
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(pos) => {
+                        let after = &rest[pos..];
+                        &after[after.find('>').map_or(after.len(), |p| p + 1)..]
+                    }
+                    None => "",
+                };
+            }
+            "br" | "p" | "div" | "tr" | "table" | "ul" | "ol" | "h1" | "h2" | "h3" | "h4"
+            | "h5" | "h6" | "blockquote" | "hr" => text.push('\n'),
+            "li" if !tag.starts_with('/') => text.push_str("\n- "),
+            "td" | "th" if tag.starts_with('/') => text.push('\t'),
+            _ => {}
+        }
+    }
+    text.push_str(rest);
+
+    // 解码常见 HTML 实体
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    // 合并多余的空白和空行
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() && lines.last().is_none_or(|l: &String| l.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
 }
 
 // 加载配置文件
 fn get_app_config() -> AppConfig {
-    return Config::builder()
+    Config::builder()
         .add_source(File::with_name("app_config.json"))
         .build()
         .unwrap()
         .try_deserialize()
-        .unwrap();
+        .unwrap()
 }
 
 // 创建 SMTP 传输
-fn create_smtp_transport(email_config: &EmailConfig) -> Result<SmtpTransport, SmtpError> {
-    // 创建 SMTP 凭据
-    let creds = Credentials::new(
-        email_config.email_account.clone(),
-        email_config.email_password.clone(),
-    );
-
-    // 创建 TLS 参数
-    let tls_parameters = TlsParameters::new(email_config.smtp_server.clone()).unwrap_or_else(|e| {
-        error!("Failed to create TLS parameters: {}", e);
-        std::process::exit(1);
-    });
-
-    // 根据 SMTP 端口选择 TLS 类型
-    let tls = match email_config.smtp_port {
-        465 => Tls::Wrapper(tls_parameters),
-        587 => Tls::Required(tls_parameters),
-        _ => Tls::Opportunistic(tls_parameters),
+fn create_smtp_transport(
+    email_config: &EmailConfig,
+    relay: &RelayConfig,
+    tls: Tls,
+    access_token: Option<&str>,
+) -> AsyncSmtpTransport<Tokio1Executor> {
+    // 创建 SMTP 凭据，中继可以使用自己的账号，使用 OAuth2 时以访问令牌代替密码
+    let account = relay
+        .email_account
+        .clone()
+        .unwrap_or_else(|| email_config.email_account.clone());
+    let (creds, mechanisms) = match access_token {
+        Some(access_token) => (
+            Credentials::new(account, access_token.to_string()),
+            vec![Mechanism::Xoauth2],
+        ),
+        None => (
+            Credentials::new(
+                account,
+                relay
+                    .email_password
+                    .clone()
+                    .unwrap_or_else(|| email_config.email_password.clone()),
+            ),
+            vec![Mechanism::Plain, Mechanism::Login],
+        ),
     };
 
-    // 创建 SMTP 传输
-    let smtp_transport = SmtpTransport::relay(&email_config.smtp_server)
-        .unwrap_or_else(|e| {
-            error!("Failed to create SMTP transport: {}", e);
-            std::process::exit(1);
-        })
+    // 创建连接池配置
+    let pool_config = PoolConfig::new()
+        .max_size(email_config.smtp_pool_max_size)
+        .idle_timeout(Duration::from_secs(email_config.smtp_pool_idle_timeout));
+
+    // 创建 SMTP 传输，TLS 方式由配置决定
+    AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&relay.smtp_server)
         .credentials(creds)
-        .port(email_config.smtp_port)
+        .authentication(mechanisms)
+        .port(relay.smtp_port)
         .tls(tls)
-        .build();
-
-    Ok(smtp_transport)
+        .timeout(Some(Duration::from_secs(email_config.smtp_send_timeout)))
+        .pool_config(pool_config)
+        .build()
 }
 
 #[tokio::main]
 async fn main() {
+    // 子命令：生成 API key 的哈希，不启动服务
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("hash-key") {
+        auth::hash_key_command(args.next());
+        return;
+    }
+
     // 初始化日志
     tracing_subscriber::fmt()
         .with_timer(tracing_subscriber::fmt::time::SystemTime)
@@ -331,13 +1345,26 @@ async fn main() {
     let app_config = get_app_config();
     info!("Configuration loaded successfully");
 
-    // 创建 SMTP 传输
+    // 为每个账户创建 SMTP 传输
+    if app_config.accounts.contains_key(DEFAULT_ACCOUNT) {
+        error!(
+            "Account name {} is reserved for the email section",
+            DEFAULT_ACCOUNT
+        );
+        std::process::exit(1);
+    }
+    let mut accounts = HashMap::new();
+    accounts.insert(
+        DEFAULT_ACCOUNT.to_string(),
+        SmtpAccount::new(DEFAULT_ACCOUNT, &app_config.email),
+    );
+    for (name, config) in &app_config.accounts {
+        accounts.insert(name.clone(), SmtpAccount::new(name, config));
+    }
     info!(
-        "Configuring SMTP transport for server: {}:{} with TLS",
-        app_config.email.smtp_server, app_config.email.smtp_port
+        "SMTP transport configured for {} account(s)",
+        accounts.len()
     );
-    let smtp_transport = create_smtp_transport(&app_config.email).unwrap();
-    info!("SMTP transport configured successfully");
 
     // 启动服务器
     let addr = format!(
@@ -346,16 +1373,107 @@ async fn main() {
     );
     info!("Server starting on {}", addr);
 
+    let body_limit = request_body_limit(&app_config.server);
+    let idempotency_window = Duration::from_secs(app_config.server.idempotency_window);
+
+    // 加载 API key，包括运行时创建的 key
+    let key_store_data = keys::load(&app_config.server.key_store).unwrap_or_else(|e| {
+        error!("Failed to load API key store: {}", e);
+        std::process::exit(1);
+    });
+    let api_keys = ApiKeys::new(&app_config.server, &key_store_data).unwrap_or_else(|e| {
+        error!("Failed to load API keys: {}", e);
+        std::process::exit(1);
+    });
+    if app_config.server.key_rotation_overlap > keys::MAX_ROTATION_OVERLAP {
+        error!(
+            "key_rotation_overlap must be at most {} seconds",
+            keys::MAX_ROTATION_OVERLAP
+        );
+        std::process::exit(1);
+    }
+    let key_store = KeyStore::new(&app_config.server.key_store, key_store_data);
+
+    // 打开发送队列
+    info!("Opening message queue in {}", app_config.queue.dir);
+    let queue = Queue::open(&app_config.queue).unwrap_or_else(|e| {
+        error!("Failed to open message queue: {}", e);
+        std::process::exit(1);
+    });
+
+    // 幂等键保存在队列目录中，与队列一样在重启后保留
+    let idempotency = IdempotencyStore::open(
+        &std::path::Path::new(&app_config.queue.dir).join("idempotency"),
+        idempotency_window,
+    )
+    .unwrap_or_else(|e| {
+        error!("Failed to open idempotency store: {}", e);
+        std::process::exit(1);
+    });
+
+    // 加载邮件模板
+    info!("Loading templates from {}", app_config.templates.dir);
+    let templates = TemplateStore::open(&app_config.templates).unwrap_or_else(|e| {
+        error!("Failed to load templates: {}", e);
+        std::process::exit(1);
+    });
+
     // 创建应用状态
     let state = Arc::new(AppState {
-        rate_limit: Mutex::new(RateLimit::new()),
-        smtp_transport,
+        api_keys,
+        key_store,
+        idempotency,
+        queue,
+        templates,
+        accounts,
         app_config,
     });
 
+    // 启动后台投递任务
+    tokio::spawn(queue::run_worker(state.clone()));
+
     // 构建路由
     let app = Router::new()
         .route("/send-email", post(send_email))
+        .route("/send-email/multipart", post(send_email_multipart))
+        .route("/send-batch", post(batch::send_batch))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency::idempotency,
+        ))
+        .route("/messages", get(list_messages))
+        .route("/messages/{id}", get(get_message).delete(cancel_message))
+        .route(
+            "/templates",
+            get(templates::list_templates).post(templates::create_template),
+        )
+        .route(
+            "/templates/{name}",
+            get(templates::get_template)
+                .put(templates::put_template)
+                .delete(templates::delete_template),
+        )
+        .route("/templates/{name}/render", post(templates::render_template))
+        .route(
+            "/stub/messages",
+            get(transport::list_stub_messages).delete(transport::clear_stub_messages),
+        )
+        .route(
+            "/admin/api-keys",
+            get(keys::list_api_keys).post(keys::create_api_key),
+        )
+        .route("/admin/api-keys/{name}", delete(keys::delete_api_key))
+        .route("/admin/api-keys/{name}/rotate", post(keys::rotate_api_key))
+        .route(
+            "/admin/api-keys/{name}/disable",
+            post(keys::disable_api_key),
+        )
+        .route("/admin/api-keys/{name}/enable", post(keys::enable_api_key))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(DefaultBodyLimit::max(body_limit))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
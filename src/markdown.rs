@@ -0,0 +1,43 @@
+use ammonia::Builder;
+use pulldown_cmark::{html, Options, Parser};
+
+// 邮件客户端大多会忽略 <style>，样式直接写在标签上
+const WRAPPER_STYLE: &str =
+    "font-family: -apple-system, 'Segoe UI', Helvetica, Arial, sans-serif; font-size: 14px; line-height: 1.6; color: #24292f;";
+const TAG_STYLES: &[(&str, &str)] = &[
+    ("h1", "font-size: 24px; margin: 24px 0 16px; border-bottom: 1px solid #d0d7de; padding-bottom: 8px;"),
+    ("h2", "font-size: 20px; margin: 24px 0 16px; border-bottom: 1px solid #d0d7de; padding-bottom: 6px;"),
+    ("h3", "font-size: 16px; margin: 24px 0 16px;"),
+    ("h4", "font-size: 14px; margin: 24px 0 16px;"),
+    ("p", "margin: 0 0 16px;"),
+    ("a", "color: #0969da; text-decoration: underline;"),
+    ("ul", "margin: 0 0 16px; padding-left: 2em;"),
+    ("ol", "margin: 0 0 16px; padding-left: 2em;"),
+    ("blockquote", "margin: 0 0 16px; padding: 0 1em; color: #57606a; border-left: 4px solid #d0d7de;"),
+    ("code", "font-family: Consolas, Menlo, monospace; font-size: 90%; background-color: #f6f8fa; padding: 2px 4px; border-radius: 4px;"),
+    ("pre", "font-family: Consolas, Menlo, monospace; font-size: 90%; background-color: #f6f8fa; padding: 12px; border-radius: 6px; overflow: auto; margin: 0 0 16px;"),
+    ("table", "border-collapse: collapse; margin: 0 0 16px;"),
+    ("th", "border: 1px solid #d0d7de; padding: 6px 12px; background-color: #f6f8fa;"),
+    ("td", "border: 1px solid #d0d7de; padding: 6px 12px;"),
+    ("hr", "border: 0; border-top: 1px solid #d0d7de; margin: 24px 0;"),
+    ("img", "max-width: 100%;"),
+];
+
+// 将 Markdown 渲染为 HTML，清理掉脚本等不安全内容后加上内联样式
+pub fn to_html(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let mut raw = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut raw, Parser::new_ext(markdown, options));
+
+    let mut sanitizer = Builder::default();
+    for (tag, style) in TAG_STYLES {
+        sanitizer.set_tag_attribute_value(*tag, "style", *style);
+    }
+    let body = sanitizer.clean(&raw).to_string();
+
+    format!("<div style=\"{}\">{}</div>", WRAPPER_STYLE, body)
+}
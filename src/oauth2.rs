@@ -0,0 +1,323 @@
+use crate::queue::write_atomic;
+use serde::Deserialize;
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+// 访问令牌到期前提前刷新的时间
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+// OAuth2 配置，使用 refresh token 换取 XOAUTH2 所需的访问令牌
+#[derive(Debug, Deserialize, Clone)]
+pub struct OAuth2Config {
+    pub token_url: String, // 令牌端点，例如 https://oauth2.googleapis.com/token
+    pub client_id: String,
+    pub client_secret: String,
+    pub refresh_token: String,
+    #[serde(default)] // 申请的权限范围，可选
+    pub scope: Option<String>,
+    #[serde(default)] // 保存轮换后的 refresh token 的文件，存在时优先于 refresh_token
+    pub refresh_token_file: Option<String>,
+}
+
+// 令牌端点的响应
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    #[serde(default = "default_expires_in")]
+    expires_in: u64,
+    #[serde(default)] // 部分服务商会轮换 refresh token
+    refresh_token: Option<String>,
+}
+
+// 未返回有效期时按 1 小时计算
+fn default_expires_in() -> u64 {
+    3600
+}
+
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+struct TokenState {
+    refresh_token: String,
+    cached: Option<CachedToken>,
+}
+
+// 访问令牌提供者，缓存令牌并在过期前刷新
+pub struct TokenProvider {
+    config: OAuth2Config,
+    client: reqwest::Client,
+    state: Mutex<TokenState>,
+}
+
+impl TokenProvider {
+    // timeout 同时限制连接和整个令牌请求，避免令牌端点无响应时投递一直等待
+    pub fn new(config: &OAuth2Config, timeout: Duration) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .build()
+            .map_err(|e| format!("failed to create OAuth2 client: {}", e))?;
+
+        // 重启后继续使用上次保存的 refresh token，旧的可能已被服务商吊销
+        let mut refresh_token = config.refresh_token.clone();
+        if let Some(path) = &config.refresh_token_file {
+            match std::fs::read_to_string(path) {
+                Ok(saved) if !saved.trim().is_empty() => {
+                    debug!("Using OAuth2 refresh token saved in {}", path);
+                    refresh_token = saved.trim().to_string();
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("failed to read {}: {}", path, e)),
+            }
+        }
+
+        Ok(TokenProvider {
+            config: config.clone(),
+            client,
+            state: Mutex::new(TokenState {
+                refresh_token,
+                cached: None,
+            }),
+        })
+    }
+
+    pub fn token_url(&self) -> &str {
+        &self.config.token_url
+    }
+
+    // 返回有效的访问令牌，没有缓存或即将过期时向令牌端点申请
+    pub async fn access_token(&self) -> Result<String, String> {
+        // 持有锁直到刷新完成，避免并发投递重复刷新
+        let mut state = self.state.lock().await;
+        if let Some(cached) = &state.cached {
+            if cached.expires_at > Instant::now() + REFRESH_MARGIN {
+                return Ok(cached.access_token.clone());
+            }
+        }
+
+        debug!(
+            "Refreshing OAuth2 access token from {}",
+            self.config.token_url
+        );
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("client_id", &self.config.client_id),
+            ("client_secret", &self.config.client_secret),
+            ("refresh_token", &state.refresh_token),
+        ];
+        if let Some(scope) = &self.config.scope {
+            form.push(("scope", scope));
+        }
+        let response = self
+            .client
+            .post(&self.config.token_url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| format!("OAuth2 token request failed: {}", e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            warn!("OAuth2 token endpoint returned {}: {}", status, body);
+            return Err(format!(
+                "OAuth2 token endpoint returned {}: {}",
+                status, body
+            ));
+        }
+        let token = response
+            .json::<TokenResponse>()
+            .await
+            .map_err(|e| format!("Invalid OAuth2 token response: {}", e))?;
+
+        info!(
+            "Obtained OAuth2 access token, expires in {}s",
+            token.expires_in
+        );
+        if let Some(refresh_token) = token
+            .refresh_token
+            .filter(|refresh_token| *refresh_token != state.refresh_token)
+        {
+            self.save_refresh_token(&refresh_token).await;
+            state.refresh_token = refresh_token;
+        }
+        state.cached = Some(CachedToken {
+            access_token: token.access_token.clone(),
+            expires_at: Instant::now() + Duration::from_secs(token.expires_in),
+        });
+        Ok(token.access_token)
+    }
+
+    // 服务器拒绝令牌时丢弃缓存，下次投递重新申请
+    pub async fn invalidate(&self) {
+        self.state.lock().await.cached = None;
+    }
+
+    // 保存失败不影响本次投递，新令牌仍在内存中使用
+    async fn save_refresh_token(&self, refresh_token: &str) {
+        let Some(path) = &self.config.refresh_token_file else {
+            warn!(
+                "OAuth2 provider rotated the refresh token, set refresh_token_file or update refresh_token in the config before restarting"
+            );
+            return;
+        };
+        match write_atomic(Path::new(path), refresh_token.as_bytes()).await {
+            Ok(()) => info!("Saved rotated OAuth2 refresh token to {}", path),
+            Err(e) => warn!(
+                "Failed to save rotated OAuth2 refresh token to {}: {}",
+                path, e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::StatusCode, routing::post, Form, Json, Router};
+    use serde_json::{json, Value};
+    use std::{collections::HashMap, sync::Arc};
+    use tokio::net::TcpListener;
+
+    // 本地令牌端点，记录收到的请求并返回固定的响应
+    struct Stub {
+        status: StatusCode,
+        body: Value,
+        delay: Duration,
+        requests: std::sync::Mutex<Vec<HashMap<String, String>>>,
+    }
+
+    impl Stub {
+        fn requests(&self) -> Vec<HashMap<String, String>> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    async fn token(
+        State(stub): State<Arc<Stub>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> (StatusCode, Json<Value>) {
+        stub.requests.lock().unwrap().push(form);
+        tokio::time::sleep(stub.delay).await;
+        (stub.status, Json(stub.body.clone()))
+    }
+
+    async fn stub(status: StatusCode, body: Value, delay: Duration) -> (Arc<Stub>, String) {
+        let stub = Arc::new(Stub {
+            status,
+            body,
+            delay,
+            requests: std::sync::Mutex::new(Vec::new()),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/token", post(token))
+            .with_state(stub.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (stub, url)
+    }
+
+    fn config(token_url: &str) -> OAuth2Config {
+        OAuth2Config {
+            token_url: token_url.to_string(),
+            client_id: "client".to_string(),
+            client_secret: "secret".to_string(),
+            refresh_token: "configured".to_string(),
+            scope: None,
+            refresh_token_file: None,
+        }
+    }
+
+    fn provider(config: &OAuth2Config) -> TokenProvider {
+        TokenProvider::new(config, Duration::from_millis(500)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn caches_token_until_invalidated() {
+        let body = json!({"access_token": "a1", "expires_in": 3600});
+        let (stub, url) = stub(StatusCode::OK, body, Duration::ZERO).await;
+        let provider = provider(&config(&url));
+
+        assert_eq!(provider.access_token().await.unwrap(), "a1");
+        assert_eq!(provider.access_token().await.unwrap(), "a1");
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["grant_type"], "refresh_token");
+        assert_eq!(requests[0]["client_id"], "client");
+        assert_eq!(requests[0]["refresh_token"], "configured");
+
+        provider.invalidate().await;
+        assert_eq!(provider.access_token().await.unwrap(), "a1");
+        assert_eq!(stub.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refreshes_token_close_to_expiry() {
+        // 有效期短于 REFRESH_MARGIN 的令牌每次都会刷新
+        let body = json!({"access_token": "a1", "expires_in": 30});
+        let (stub, url) = stub(StatusCode::OK, body, Duration::ZERO).await;
+        let provider = provider(&config(&url));
+
+        provider.access_token().await.unwrap();
+        provider.access_token().await.unwrap();
+        assert_eq!(stub.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn error_status_is_an_error() {
+        let body = json!({"error": "invalid_grant"});
+        let (_stub, url) = stub(StatusCode::BAD_REQUEST, body, Duration::ZERO).await;
+        let provider = provider(&config(&url));
+
+        let error = provider.access_token().await.unwrap_err();
+        assert!(error.contains("400"), "{}", error);
+        assert!(error.contains("invalid_grant"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn slow_endpoint_times_out() {
+        let body = json!({"access_token": "a1"});
+        let (_stub, url) = stub(StatusCode::OK, body, Duration::from_secs(5)).await;
+        let provider = provider(&config(&url));
+
+        let started = Instant::now();
+        let error = provider.access_token().await.unwrap_err();
+        assert!(error.contains("request failed"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn rotated_refresh_token_is_saved() {
+        let body = json!({"access_token": "a1", "refresh_token": "rotated"});
+        let (stub, url) = stub(StatusCode::OK, body, Duration::ZERO).await;
+        let path = std::env::temp_dir().join(format!("refresh-token-{}", uuid::Uuid::new_v4()));
+        let config = OAuth2Config {
+            refresh_token_file: Some(path.to_string_lossy().into_owned()),
+            ..config(&url)
+        };
+
+        let first = provider(&config);
+        first.access_token().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "rotated");
+        first.invalidate().await;
+        first.access_token().await.unwrap();
+
+        // 重启后使用保存的 refresh token
+        provider(&config).access_token().await.unwrap();
+        let sent = stub
+            .requests()
+            .iter()
+            .map(|form| form["refresh_token"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, ["configured", "rotated", "rotated"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,606 @@
+use crate::{
+    relay::{Delivery, DeliveryError},
+    AppState, DEFAULT_ACCOUNT,
+};
+use chrono::{DateTime, Utc};
+use lettre::{address::Envelope, Address};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Notify, Semaphore};
+use tracing::{debug, error, info, warn};
+
+// 队列配置
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueueConfig {
+    #[serde(default = "default_queue_dir")] // 队列目录
+    pub dir: String,
+    #[serde(default = "default_max_attempts")] // 最大投递次数，超过后移入死信目录
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_backoff")] // 首次重试间隔（秒）
+    pub initial_backoff: u64,
+    #[serde(default = "default_max_backoff")] // 最大重试间隔（秒）
+    pub max_backoff: u64,
+    #[serde(default = "default_concurrency")] // 同时投递的邮件数
+    pub concurrency: usize,
+    #[serde(default = "default_sent_retention")] // 已发送和已取消邮件状态记录的保留时间（秒）
+    pub sent_retention: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            dir: default_queue_dir(),
+            max_attempts: default_max_attempts(),
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff(),
+            concurrency: default_concurrency(),
+            sent_retention: default_sent_retention(),
+        }
+    }
+}
+
+// 默认队列目录
+fn default_queue_dir() -> String {
+    "queue".to_string()
+}
+
+// 默认最大投递次数
+fn default_max_attempts() -> u32 {
+    8
+}
+
+// 默认首次重试间隔：30 秒
+fn default_initial_backoff() -> u64 {
+    30
+}
+
+// 默认最大重试间隔：1 小时
+fn default_max_backoff() -> u64 {
+    3600
+}
+
+// 默认并发投递数
+fn default_concurrency() -> usize {
+    4
+}
+
+// 默认已发送记录保留时间：7 天
+fn default_sent_retention() -> u64 {
+    7 * 24 * 3600
+}
+
+// 邮件投递状态
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    #[default]
+    Queued,
+    Scheduled,
+    Sending,
+    Sent,
+    Deferred,
+    Failed,
+    Cancelled,
+}
+
+// 邮件状态记录，未发送时与 .eml 原始邮件一起保存
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageRecord {
+    pub id: String,
+    #[serde(default)]
+    pub status: MessageStatus,
+    #[serde(default = "default_account")] // 投递使用的 SMTP 账户
+    pub account: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] // 提交邮件的 API 客户端
+    pub client: Option<String>,
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub updated_at: DateTime<Utc>,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")] // 下一次投递时间，仅在等待投递时存在
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")] // 最近一次失败原因
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] // 投递成功时 SMTP 服务器的响应
+    pub smtp_response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] // 最近一次投递使用的中继
+    pub relay: Option<String>,
+}
+
+impl MessageRecord {
+    // 还原 SMTP 信封
+    fn envelope(&self) -> Result<Envelope, String> {
+        let from = match &self.from {
+            Some(from) => Some(from.parse::<Address>().map_err(|e| e.to_string())?),
+            None => None,
+        };
+        let to = self
+            .to
+            .iter()
+            .map(|to| to.parse::<Address>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        Envelope::new(from, to).map_err(|e| e.to_string())
+    }
+}
+
+// 旧版本的记录没有账户，使用默认账户
+fn default_account() -> String {
+    DEFAULT_ACCOUNT.to_string()
+}
+
+// 取消邮件失败的原因
+pub enum CancelError {
+    NotFound,
+    NotCancellable(MessageStatus),
+}
+
+// 邮件列表筛选条件
+#[derive(Debug, Deserialize)]
+pub struct MessageFilter {
+    pub status: Option<MessageStatus>,
+    pub to: Option<String>, // 收件人地址
+    pub account: Option<String>,
+    pub client: Option<String>, // 提交邮件的 API 客户端
+    #[serde(default = "default_list_limit")]
+    pub limit: usize,
+}
+
+// 默认列表返回条数
+fn default_list_limit() -> usize {
+    100
+}
+
+// 持久化发送队列
+// pending 目录保存待投递邮件，sent 目录保存已发送记录，dead 目录保存最终失败的邮件，
+// cancelled 目录保存已取消的定时邮件记录
+pub struct Queue {
+    config: QueueConfig,
+    pending_dir: PathBuf,
+    sent_dir: PathBuf,
+    dead_dir: PathBuf,
+    cancelled_dir: PathBuf,
+    messages: Mutex<HashMap<String, MessageRecord>>,
+    notify: Notify,
+}
+
+impl Queue {
+    // 打开队列目录并加载所有邮件记录
+    pub fn open(config: &QueueConfig) -> io::Result<Self> {
+        let dir = Path::new(&config.dir);
+        let pending_dir = dir.join("pending");
+        let sent_dir = dir.join("sent");
+        let dead_dir = dir.join("dead");
+        let cancelled_dir = dir.join("cancelled");
+        let mut messages = HashMap::new();
+        // sent 和 dead 目录中的记录状态由所在目录决定
+        for (dir, status) in [
+            (&pending_dir, None),
+            (&sent_dir, Some(MessageStatus::Sent)),
+            (&dead_dir, Some(MessageStatus::Failed)),
+            (&cancelled_dir, Some(MessageStatus::Cancelled)),
+        ] {
+            std::fs::create_dir_all(dir)?;
+            load_records(dir, status, &mut messages)?;
+        }
+
+        // 上次退出时未完成投递的邮件重新排队
+        for message in messages.values_mut() {
+            if message.status == MessageStatus::Sending {
+                message.status = MessageStatus::Queued;
+            }
+        }
+        info!("Loaded {} message record(s) from queue", messages.len());
+
+        Ok(Queue {
+            config: config.clone(),
+            pending_dir,
+            sent_dir,
+            dead_dir,
+            cancelled_dir,
+            messages: Mutex::new(messages),
+            notify: Notify::new(),
+        })
+    }
+
+    // 将邮件写入队列，指定 send_at 时到期后才投递
+    pub async fn enqueue(
+        &self,
+        id: &str,
+        account: &str,
+        client: &str,
+        envelope: &Envelope,
+        raw: Vec<u8>,
+        send_at: Option<DateTime<Utc>>,
+    ) -> io::Result<MessageRecord> {
+        let now = Utc::now();
+        let send_at = send_at.filter(|send_at| *send_at > now);
+        let message = MessageRecord {
+            id: id.to_string(),
+            account: account.to_string(),
+            client: Some(client.to_string()),
+            status: if send_at.is_some() {
+                MessageStatus::Scheduled
+            } else {
+                MessageStatus::Queued
+            },
+            from: envelope.from().map(|from| from.to_string()),
+            to: envelope.to().iter().map(|to| to.to_string()).collect(),
+            created_at: now,
+            updated_at: now,
+            attempts: 0,
+            next_attempt_at: Some(send_at.unwrap_or(now)),
+            last_error: None,
+            smtp_response: None,
+            relay: None,
+        };
+
+        // 先写原始邮件，再写记录，记录存在即表示邮件完整
+        write_atomic(&self.pending_dir.join(format!("{}.eml", id)), &raw).await?;
+        self.save(&self.pending_dir, &message).await?;
+        debug!("Message {} written to queue", id);
+
+        self.messages
+            .lock()
+            .unwrap()
+            .insert(message.id.clone(), message.clone());
+        self.notify.notify_one();
+        Ok(message)
+    }
+
+    // 取消尚未开始投递的邮件
+    pub async fn cancel(&self, id: &str) -> Result<MessageRecord, CancelError> {
+        let message = {
+            let mut messages = self.messages.lock().unwrap();
+            let message = messages.get_mut(id).ok_or(CancelError::NotFound)?;
+            if !matches!(
+                message.status,
+                MessageStatus::Queued | MessageStatus::Scheduled | MessageStatus::Deferred
+            ) {
+                return Err(CancelError::NotCancellable(message.status));
+            }
+            message.status = MessageStatus::Cancelled;
+            message.updated_at = Utc::now();
+            message.next_attempt_at = None;
+            message.clone()
+        };
+
+        if let Err(e) = self.save(&self.cancelled_dir, &message).await {
+            error!("Failed to save cancelled record {}: {}", message.id, e);
+        }
+        for ext in ["json", "eml"] {
+            let path = self.pending_dir.join(format!("{}.{}", message.id, ext));
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                error!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+        info!("Message {} cancelled", message.id);
+        Ok(message)
+    }
+
+    // 取出到期的邮件并标记为发送中，同时返回下一封邮件的到期时间
+    fn take_due(&self) -> (Vec<MessageRecord>, Option<DateTime<Utc>>) {
+        let now = Utc::now();
+        let mut messages = self.messages.lock().unwrap();
+        let mut due = Vec::new();
+        let mut next = None;
+        for message in messages.values_mut().filter(|m| {
+            matches!(
+                m.status,
+                MessageStatus::Queued | MessageStatus::Scheduled | MessageStatus::Deferred
+            )
+        }) {
+            let Some(next_attempt_at) = message.next_attempt_at else {
+                continue;
+            };
+            if next_attempt_at <= now {
+                message.status = MessageStatus::Sending;
+                message.updated_at = now;
+                due.push(message.clone());
+            } else if next.is_none_or(|next| next_attempt_at < next) {
+                next = Some(next_attempt_at);
+            }
+        }
+        (due, next)
+    }
+
+    // 查询单封邮件的状态
+    pub fn get(&self, id: &str) -> Option<MessageRecord> {
+        self.messages.lock().unwrap().get(id).cloned()
+    }
+
+    // 筛选邮件记录，最新的在前
+    pub fn list(&self, filter: &MessageFilter) -> Vec<MessageRecord> {
+        let messages = self.messages.lock().unwrap();
+        let mut records: Vec<_> = messages
+            .values()
+            .filter(|m| filter.status.is_none_or(|status| m.status == status))
+            .filter(|m| {
+                filter
+                    .to
+                    .as_ref()
+                    .is_none_or(|to| m.to.iter().any(|addr| addr.eq_ignore_ascii_case(to)))
+            })
+            .filter(|m| filter.account.as_ref().is_none_or(|a| m.account == *a))
+            .filter(|m| {
+                filter
+                    .client
+                    .as_ref()
+                    .is_none_or(|c| m.client.as_ref() == Some(c))
+            })
+            .cloned()
+            .collect();
+        records.sort_by_key(|m| Reverse(m.created_at));
+        records.truncate(filter.limit);
+        records
+    }
+
+    // 投递成功，删除原始邮件并保存已发送记录
+    async fn complete(&self, mut message: MessageRecord, response: String) {
+        message.status = MessageStatus::Sent;
+        message.updated_at = Utc::now();
+        message.next_attempt_at = None;
+        message.smtp_response = Some(response);
+        self.messages
+            .lock()
+            .unwrap()
+            .insert(message.id.clone(), message.clone());
+
+        if let Err(e) = self.save(&self.sent_dir, &message).await {
+            error!("Failed to save sent record {}: {}", message.id, e);
+        }
+        for ext in ["json", "eml"] {
+            let path = self.pending_dir.join(format!("{}.{}", message.id, ext));
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                error!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    // 清理超过保留时间的已发送和已取消记录
+    async fn prune_finished(&self) {
+        let cutoff = Utc::now() - Duration::from_secs(self.config.sent_retention);
+        let expired = {
+            let mut messages = self.messages.lock().unwrap();
+            let expired = messages
+                .values()
+                .filter(|m| {
+                    matches!(m.status, MessageStatus::Sent | MessageStatus::Cancelled)
+                        && m.updated_at < cutoff
+                })
+                .map(|m| (m.id.clone(), m.status))
+                .collect::<Vec<_>>();
+            for (id, _) in &expired {
+                messages.remove(id);
+            }
+            expired
+        };
+
+        for (id, status) in &expired {
+            let dir = match status {
+                MessageStatus::Cancelled => &self.cancelled_dir,
+                _ => &self.sent_dir,
+            };
+            let path = dir.join(format!("{}.json", id));
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                error!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+        if !expired.is_empty() {
+            info!("Pruned {} expired message record(s)", expired.len());
+        }
+    }
+
+    // 暂时失败，按指数退避安排下一次投递
+    async fn defer(&self, mut message: MessageRecord, reason: String) {
+        if message.attempts >= self.config.max_attempts {
+            warn!(
+                "Message {} failed after {} attempt(s), giving up",
+                message.id, message.attempts
+            );
+            return self.dead_letter(message, reason).await;
+        }
+
+        let backoff = self
+            .config
+            .initial_backoff
+            .saturating_mul(2u64.saturating_pow(message.attempts - 1))
+            .min(self.config.max_backoff);
+        message.status = MessageStatus::Deferred;
+        message.updated_at = Utc::now();
+        message.next_attempt_at = Some(message.updated_at + Duration::from_secs(backoff));
+        message.last_error = Some(reason);
+        info!(
+            "Message {} deferred, next attempt in {}s",
+            message.id, backoff
+        );
+
+        if let Err(e) = self.save(&self.pending_dir, &message).await {
+            error!("Failed to update queue entry {}: {}", message.id, e);
+        }
+        self.messages
+            .lock()
+            .unwrap()
+            .insert(message.id.clone(), message);
+    }
+
+    // 永久失败，移入死信目录
+    async fn dead_letter(&self, mut message: MessageRecord, reason: String) {
+        message.status = MessageStatus::Failed;
+        message.updated_at = Utc::now();
+        message.next_attempt_at = None;
+        message.last_error = Some(reason);
+        self.messages
+            .lock()
+            .unwrap()
+            .insert(message.id.clone(), message.clone());
+
+        let result = async {
+            self.save(&self.dead_dir, &message).await?;
+            let eml = format!("{}.eml", message.id);
+            if tokio::fs::try_exists(self.pending_dir.join(&eml)).await? {
+                tokio::fs::rename(self.pending_dir.join(&eml), self.dead_dir.join(&eml)).await?;
+            }
+            tokio::fs::remove_file(self.pending_dir.join(format!("{}.json", message.id))).await
+        }
+        .await;
+        match result {
+            Ok(()) => warn!("Message {} moved to dead letter queue", message.id),
+            Err(e) => error!(
+                "Failed to move message {} to dead letter queue: {}",
+                message.id, e
+            ),
+        }
+    }
+
+    async fn save(&self, dir: &Path, message: &MessageRecord) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(message).map_err(io::Error::other)?;
+        write_atomic(&dir.join(format!("{}.json", message.id)), &data).await
+    }
+}
+
+// 加载目录中的邮件记录
+fn load_records(
+    dir: &Path,
+    status: Option<MessageStatus>,
+    messages: &mut HashMap<String, MessageRecord>,
+) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| {
+                serde_json::from_slice::<MessageRecord>(&data).map_err(|e| e.to_string())
+            }) {
+            Ok(mut message) => {
+                if let Some(status) = status {
+                    message.status = status;
+                    message.next_attempt_at = None;
+                }
+                messages.insert(message.id.clone(), message);
+            }
+            Err(e) => warn!("Skipping unreadable queue entry {}: {}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+// 先写临时文件再重命名，避免崩溃时留下不完整的文件
+pub async fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, data).await?;
+    tokio::fs::rename(&tmp, path).await
+}
+
+// 后台投递任务
+pub async fn run_worker(state: Arc<AppState>) {
+    let semaphore = Arc::new(Semaphore::new(state.app_config.queue.concurrency.max(1)));
+    info!("Queue worker started");
+    let mut last_prune = Instant::now();
+    state.queue.prune_finished().await;
+
+    loop {
+        // 每小时清理一次过期的已发送和已取消记录
+        if last_prune.elapsed() >= Duration::from_secs(3600) {
+            state.queue.prune_finished().await;
+            last_prune = Instant::now();
+        }
+
+        let (due, next) = state.queue.take_due();
+        for message in due {
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let state = state.clone();
+            tokio::spawn(async move {
+                attempt_delivery(&state, message).await;
+                drop(permit);
+                state.queue.notify.notify_one();
+            });
+        }
+
+        // 等待新邮件入队或下一封邮件到期
+        let wait = next
+            .and_then(|next| (next - Utc::now()).to_std().ok())
+            .unwrap_or(Duration::from_secs(60));
+        tokio::select! {
+            _ = state.queue.notify.notified() => {}
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+// 投递单封邮件，根据 SMTP 响应决定重试还是放弃
+async fn attempt_delivery(state: &AppState, mut message: MessageRecord) {
+    let queue = &state.queue;
+    message.attempts += 1;
+    info!(
+        "Delivering message {} (attempt {})",
+        message.id, message.attempts
+    );
+
+    let envelope = match message.envelope() {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            error!("Message {} has an invalid envelope: {}", message.id, e);
+            return queue.dead_letter(message, e).await;
+        }
+    };
+    let raw = match tokio::fs::read(queue.pending_dir.join(format!("{}.eml", message.id))).await {
+        Ok(raw) => raw,
+        Err(e) => {
+            error!("Failed to read message {}: {}", message.id, e);
+            return queue.dead_letter(message, e.to_string()).await;
+        }
+    };
+
+    let Some(account) = state.accounts.get(&message.account) else {
+        error!(
+            "Message {} uses unknown account {}",
+            message.id, message.account
+        );
+        let error = format!("Unknown account: {}", message.account);
+        return queue.dead_letter(message, error).await;
+    };
+
+    match account.transport.send(&message.id, &envelope, &raw).await {
+        Ok(Delivery { relay, response }) => {
+            info!(
+                "Message {} from client {} sent successfully to {:?} via {}: {}",
+                message.id,
+                message.client.as_deref().unwrap_or("-"),
+                message.to,
+                relay,
+                response
+            );
+            message.relay = Some(relay);
+            queue.complete(message, response).await;
+        }
+        Err(DeliveryError::Permanent { relay, error }) => {
+            error!(
+                "Message {} permanently rejected by {}: {}",
+                message.id, relay, error
+            );
+            message.relay = Some(relay);
+            queue.dead_letter(message, error).await;
+        }
+        Err(DeliveryError::Transient { relay, error }) => {
+            warn!(
+                "Message {} temporarily failed on all relays, last error from {}: {}",
+                message.id, relay, error
+            );
+            message.relay = Some(relay);
+            queue.defer(message, error).await;
+        }
+    }
+}
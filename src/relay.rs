@@ -0,0 +1,517 @@
+use crate::{create_smtp_transport, oauth2::TokenProvider, tls::TlsSettings, EmailConfig};
+use lettre::{
+    address::Envelope,
+    transport::smtp::{client::Tls, Error as SmtpError},
+    AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+use serde::Deserialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+// 中继服务器配置
+#[derive(Debug, Deserialize, Clone)]
+pub struct RelayConfig {
+    pub smtp_server: String,
+    pub smtp_port: u16,
+    #[serde(default)] // 优先级，数字越小越优先，同一优先级的中继按权重分配
+    pub priority: u32,
+    #[serde(default = "default_relay_weight")] // 同一优先级内的权重
+    pub weight: u32,
+    #[serde(default)] // 中继使用的账号，未配置时使用账户的 email_account
+    pub email_account: Option<String>,
+    #[serde(default)] // 中继使用的密码，未配置时使用账户的 email_password
+    pub email_password: Option<String>,
+    #[serde(default)] // 中继使用的 TLS 配置，未配置时使用账户的 tls
+    pub tls: Option<TlsSettings>,
+}
+
+// 默认权重
+fn default_relay_weight() -> u32 {
+    1
+}
+
+// 单个中继及其健康状态
+struct Relay {
+    name: String,
+    priority: u32,
+    weight: u32,
+    config: RelayConfig,
+    tls: Tls,
+    transport: Mutex<RelayTransport>,
+    health: Mutex<Health>,
+}
+
+// 使用 OAuth2 时，访问令牌变化后需要用新令牌重建传输
+struct RelayTransport {
+    access_token: Option<String>,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+#[derive(Default)]
+struct Health {
+    consecutive_failures: u32,
+    open_until: Option<Instant>, // 熔断结束时间，期间优先使用其他中继
+    current_weight: i64,         // 平滑加权轮询的当前权重
+}
+
+// 投递结果
+pub struct Delivery {
+    pub relay: String,
+    pub response: String,
+}
+
+// 投递失败，relay 为最后尝试的中继
+pub enum DeliveryError {
+    Permanent { relay: String, error: String },
+    Transient { relay: String, error: String },
+}
+
+// 账户的中继列表，负责选择中继、故障转移和熔断
+pub struct RelayPool {
+    email_config: EmailConfig,
+    oauth2: Option<TokenProvider>,
+    relays: Vec<Relay>,
+    failure_threshold: u32,
+    cooldown: Duration,
+    send_timeout: Duration,
+}
+
+impl RelayPool {
+    // 未配置 relays 时使用账户的 smtp_server 和 smtp_port 作为唯一的中继
+    pub fn new(config: &EmailConfig) -> Result<Self, String> {
+        let relays = if config.relays.is_empty() {
+            vec![RelayConfig {
+                smtp_server: config.smtp_server.clone(),
+                smtp_port: config.smtp_port,
+                priority: 0,
+                weight: default_relay_weight(),
+                email_account: None,
+                email_password: None,
+                tls: None,
+            }]
+        } else {
+            config.relays.clone()
+        };
+
+        let relays = relays
+            .iter()
+            .map(|relay| {
+                let name = format!("{}:{}", relay.smtp_server, relay.smtp_port);
+                let tls = relay
+                    .tls
+                    .as_ref()
+                    .unwrap_or(&config.tls)
+                    .build(&relay.smtp_server, relay.smtp_port)
+                    .map_err(|e| format!("relay {}: {}", name, e))?;
+                Ok(Relay {
+                    transport: Mutex::new(RelayTransport {
+                        access_token: None,
+                        transport: create_smtp_transport(config, relay, tls.clone(), None),
+                    }),
+                    name,
+                    priority: relay.priority,
+                    weight: relay.weight.max(1),
+                    config: relay.clone(),
+                    tls,
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let send_timeout = Duration::from_secs(config.smtp_send_timeout);
+        Ok(RelayPool {
+            email_config: config.clone(),
+            oauth2: config
+                .oauth2
+                .as_ref()
+                .map(|oauth2| TokenProvider::new(oauth2, send_timeout))
+                .transpose()?,
+            relays,
+            failure_threshold: config.relay_failure_threshold.max(1),
+            cooldown: Duration::from_secs(config.relay_cooldown),
+            send_timeout,
+        })
+    }
+
+    // 依次尝试各个中继，中继失败时换下一个，收件人被拒绝时直接返回
+    // 所有中继都永久拒绝时返回永久失败，否则稍后重试
+    pub async fn send(&self, envelope: &Envelope, raw: &[u8]) -> Result<Delivery, DeliveryError> {
+        // 使用 OAuth2 时先取得访问令牌，等待其他投递刷新令牌的时间也计入超时
+        let access_token = match &self.oauth2 {
+            Some(oauth2) => {
+                let access_token =
+                    match tokio::time::timeout(self.send_timeout, oauth2.access_token()).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!(
+                            "Timed out after {:?} waiting for OAuth2 access token",
+                            self.send_timeout
+                        )),
+                    };
+                Some(access_token.map_err(|error| {
+                    warn!("Failed to obtain OAuth2 access token: {}", error);
+                    DeliveryError::Transient {
+                        relay: oauth2.token_url().to_string(),
+                        error,
+                    }
+                })?)
+            }
+            None => None,
+        };
+
+        let mut last_error = None;
+        let mut all_permanent = true;
+        for relay in self.order() {
+            debug!("Trying relay {}", relay.name);
+            let transport = self.transport(relay, access_token.as_deref());
+            let error =
+                match tokio::time::timeout(self.send_timeout, transport.send_raw(envelope, raw))
+                    .await
+                {
+                    Ok(Ok(response)) => {
+                        self.record_success(relay);
+                        return Ok(Delivery {
+                            relay: relay.name.clone(),
+                            response: format!(
+                                "{} {}",
+                                response.code(),
+                                response.message().collect::<Vec<_>>().join(" ")
+                            ),
+                        });
+                    }
+                    // 令牌被拒绝时丢弃缓存，作为暂时失败稍后用新令牌重试
+                    Ok(Err(e)) if self.oauth2.is_some() && is_auth_failure(&e) => {
+                        warn!("Relay {} rejected the OAuth2 access token", relay.name);
+                        if let Some(oauth2) = &self.oauth2 {
+                            oauth2.invalidate().await;
+                        }
+                        return Err(DeliveryError::Transient {
+                            relay: relay.name.clone(),
+                            error: e.to_string(),
+                        });
+                    }
+                    // 收件人被拒绝，换中继也不会成功
+                    Ok(Err(e))
+                        if e.status()
+                            .map(u16::from)
+                            .is_some_and(is_recipient_rejection) =>
+                    {
+                        return Err(DeliveryError::Permanent {
+                            relay: relay.name.clone(),
+                            error: e.to_string(),
+                        });
+                    }
+                    // 认证、问候或会话被拒绝只说明这个中继不可用
+                    Ok(Err(e)) => {
+                        all_permanent &= e.is_permanent();
+                        e.to_string()
+                    }
+                    Err(_) => {
+                        all_permanent = false;
+                        format!("Timed out after {:?}", self.send_timeout)
+                    }
+                };
+
+            warn!("Relay {} failed: {}", relay.name, error);
+            self.record_failure(relay);
+            last_error = Some((relay.name.clone(), error));
+        }
+
+        let (relay, error) = last_error.expect("relay pool is never empty");
+        Err(if all_permanent {
+            DeliveryError::Permanent { relay, error }
+        } else {
+            DeliveryError::Transient { relay, error }
+        })
+    }
+
+    // 取得中继的传输，访问令牌变化时重建
+    fn transport(
+        &self,
+        relay: &Relay,
+        access_token: Option<&str>,
+    ) -> AsyncSmtpTransport<Tokio1Executor> {
+        let mut cached = relay.transport.lock().unwrap();
+        if access_token.is_some() && cached.access_token.as_deref() != access_token {
+            debug!(
+                "Rebuilding transport for relay {} with new access token",
+                relay.name
+            );
+            *cached = RelayTransport {
+                access_token: access_token.map(str::to_string),
+                transport: create_smtp_transport(
+                    &self.email_config,
+                    &relay.config,
+                    relay.tls.clone(),
+                    access_token,
+                ),
+            };
+        }
+        cached.transport.clone()
+    }
+
+    // 按优先级排序，同一优先级内按平滑加权轮询选出第一个，熔断中的中继放在最后
+    fn order(&self) -> Vec<&Relay> {
+        let now = Instant::now();
+        let is_open = |relay: &Relay| {
+            relay
+                .health
+                .lock()
+                .unwrap()
+                .open_until
+                .is_some_and(|until| until > now)
+        };
+        let (mut available, mut open): (Vec<&Relay>, Vec<&Relay>) =
+            self.relays.iter().partition(|relay| !is_open(relay));
+
+        available.sort_by_key(|relay| (relay.priority, std::cmp::Reverse(relay.weight)));
+        let mut ordered = Vec::with_capacity(self.relays.len());
+        for group in available.chunk_by(|a, b| a.priority == b.priority) {
+            let mut group = group.to_vec();
+            let first = pick_weighted(&group);
+            ordered.push(group.remove(first));
+            ordered.extend(group);
+        }
+
+        open.sort_by_key(|relay| relay.priority);
+        ordered.extend(open);
+        ordered
+    }
+
+    fn record_success(&self, relay: &Relay) {
+        let mut health = relay.health.lock().unwrap();
+        if health.consecutive_failures >= self.failure_threshold {
+            info!("Relay {} recovered", relay.name);
+        }
+        health.consecutive_failures = 0;
+        health.open_until = None;
+    }
+
+    // 连续失败达到阈值后熔断一段时间
+    fn record_failure(&self, relay: &Relay) {
+        let mut health = relay.health.lock().unwrap();
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= self.failure_threshold {
+            warn!(
+                "Relay {} failed {} time(s) in a row, skipping it for {:?}",
+                relay.name, health.consecutive_failures, self.cooldown
+            );
+            health.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+// 认证失败（535）
+fn is_auth_failure(error: &SmtpError) -> bool {
+    error.status().is_some_and(|code| code.to_string() == "535")
+}
+
+// 收件人被拒绝（550-553），通常是 RCPT TO 的回复
+// lettre 不区分失败的阶段，只能按回复码判断；530、535、554 等认证和会话级别的拒绝不算
+fn is_recipient_rejection(code: u16) -> bool {
+    (550..=553).contains(&code)
+}
+
+// 平滑加权轮询：每次把各中继的当前权重加上其权重，选出最大的一个并减去总权重
+fn pick_weighted(group: &[&Relay]) -> usize {
+    let total = group.iter().map(|relay| relay.weight as i64).sum::<i64>();
+    let mut best = 0;
+    let mut best_weight = i64::MIN;
+    for (index, relay) in group.iter().enumerate() {
+        let mut health = relay.health.lock().unwrap();
+        health.current_weight += relay.weight as i64;
+        if health.current_weight > best_weight {
+            best = index;
+            best_weight = health.current_weight;
+        }
+    }
+    group[best].health.lock().unwrap().current_weight -= total;
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::Address;
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    fn pool(relays: serde_json::Value) -> RelayPool {
+        let config = serde_json::from_value::<EmailConfig>(json!({
+            "email_account": "user",
+            "email_password": "password",
+            "email_from": "me@example.com",
+            "email_to": "you@example.com",
+            "sender_name": "Test",
+            "smtp_send_timeout": 5,
+            "relay_failure_threshold": 2,
+            "tls": {"mode": "none"},
+            "relays": relays,
+        }))
+        .unwrap();
+        RelayPool::new(&config).unwrap()
+    }
+
+    fn relay(port: u16, priority: u32, weight: u32) -> serde_json::Value {
+        json!({"smtp_server": "127.0.0.1", "smtp_port": port, "priority": priority, "weight": weight})
+    }
+
+    fn names(relays: &[&Relay]) -> Vec<String> {
+        relays.iter().map(|relay| relay.name.clone()).collect()
+    }
+
+    // 最简单的 SMTP 服务器，AUTH 和 RCPT TO 按给定的回复应答，其他命令都接受
+    async fn stub_smtp(auth_reply: &'static str, rcpt_reply: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 stub ESMTP\r\n").await.unwrap();
+                    let mut in_data = false;
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = if in_data {
+                            if line != "." {
+                                continue;
+                            }
+                            in_data = false;
+                            "250 queued"
+                        } else {
+                            match line.get(..4).unwrap_or("").to_ascii_uppercase().as_str() {
+                                "EHLO" => "250-stub\r\n250 AUTH PLAIN LOGIN",
+                                "AUTH" => auth_reply,
+                                "RCPT" => rcpt_reply,
+                                "DATA" => {
+                                    in_data = true;
+                                    "354 go ahead"
+                                }
+                                "QUIT" => "221 bye",
+                                _ => "250 ok",
+                            }
+                        };
+                        let closing = reply.starts_with('5') || reply.starts_with("221");
+                        writer
+                            .write_all(format!("{}\r\n", reply).as_bytes())
+                            .await
+                            .unwrap();
+                        if closing {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn envelope() -> Envelope {
+        Envelope::new(
+            Some("me@example.com".parse::<Address>().unwrap()),
+            vec!["you@example.com".parse::<Address>().unwrap()],
+        )
+        .unwrap()
+    }
+
+    const RAW: &[u8] = b"Subject: test\r\n\r\nhello\r\n";
+
+    #[test]
+    fn recipient_rejections_are_final() {
+        for code in [550, 551, 552, 553] {
+            assert!(is_recipient_rejection(code), "{}", code);
+        }
+        for code in [421, 450, 530, 534, 535, 554, 556] {
+            assert!(!is_recipient_rejection(code), "{}", code);
+        }
+    }
+
+    #[tokio::test]
+    async fn pick_weighted_spreads_by_weight() {
+        let pool = pool(json!([relay(1, 0, 5), relay(2, 0, 1), relay(3, 0, 1)]));
+        let group = pool.relays.iter().collect::<Vec<_>>();
+        let picks = (0..7).map(|_| pick_weighted(&group)).collect::<Vec<_>>();
+        // 平滑加权轮询不会连续选中权重大的中继
+        assert_eq!(picks, vec![0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn order_follows_priority_and_skips_open_relays() {
+        let pool = pool(json!([
+            relay(1, 1, 1),
+            relay(2, 0, 1),
+            relay(3, 0, 3),
+            relay(4, 2, 1)
+        ]));
+        assert_eq!(
+            names(&pool.order()),
+            ["127.0.0.1:3", "127.0.0.1:2", "127.0.0.1:1", "127.0.0.1:4"]
+        );
+
+        // 熔断中的中继放在最后
+        pool.record_failure(&pool.relays[1]);
+        pool.record_failure(&pool.relays[1]);
+        pool.record_failure(&pool.relays[2]);
+        pool.record_failure(&pool.relays[2]);
+        assert_eq!(
+            names(&pool.order()),
+            ["127.0.0.1:1", "127.0.0.1:4", "127.0.0.1:2", "127.0.0.1:3"]
+        );
+
+        pool.record_success(&pool.relays[2]);
+        assert_eq!(names(&pool.order())[0], "127.0.0.1:3");
+    }
+
+    #[tokio::test]
+    async fn auth_failure_moves_to_next_relay() {
+        let bad = stub_smtp("535 authentication failed", "250 ok").await;
+        let good = stub_smtp("235 ok", "250 ok").await;
+        let pool = pool(json!([relay(bad, 0, 1), relay(good, 1, 1)]));
+
+        let delivery = pool.send(&envelope(), RAW).await.ok().unwrap();
+        assert_eq!(delivery.relay, format!("127.0.0.1:{}", good));
+        assert_eq!(delivery.response, "250 queued");
+        let health = pool.relays[0].health.lock().unwrap();
+        assert_eq!(health.consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn recipient_rejection_is_permanent() {
+        let first = stub_smtp("235 ok", "550 no such user").await;
+        let second = stub_smtp("235 ok", "250 ok").await;
+        let pool = pool(json!([relay(first, 0, 1), relay(second, 1, 1)]));
+
+        match pool.send(&envelope(), RAW).await {
+            Err(DeliveryError::Permanent { relay, .. }) => {
+                assert_eq!(relay, format!("127.0.0.1:{}", first))
+            }
+            _ => panic!("expected a permanent failure"),
+        }
+        assert_eq!(
+            pool.relays[0].health.lock().unwrap().consecutive_failures,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn rejection_by_every_relay_is_permanent() {
+        let first = stub_smtp("535 authentication failed", "250 ok").await;
+        let second = stub_smtp("554 no service", "250 ok").await;
+        let pool = pool(json!([relay(first, 0, 1), relay(second, 1, 1)]));
+
+        match pool.send(&envelope(), RAW).await {
+            Err(DeliveryError::Permanent { relay, .. }) => {
+                assert_eq!(relay, format!("127.0.0.1:{}", second))
+            }
+            _ => panic!("expected a permanent failure"),
+        }
+        for relay in &pool.relays {
+            assert_eq!(relay.health.lock().unwrap().consecutive_failures, 1);
+        }
+    }
+}
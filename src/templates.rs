@@ -0,0 +1,558 @@
+use crate::{
+    auth::{ApiClient, Scope},
+    preview_email,
+    queue::write_atomic,
+    ApiResponse, AppState, EmailError, EmailRequest, MessagePreview,
+};
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
+use handlebars::{Handlebars, RenderErrorReason, Template};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tracing::{debug, info, warn};
+
+// 模板名称的最大长度
+const MAX_NAME_LENGTH: usize = 100;
+
+// 模板配置
+#[derive(Debug, Deserialize, Clone)]
+pub struct TemplateConfig {
+    #[serde(default = "default_templates_dir")] // 模板目录，每个模板保存为 <名称>.json
+    pub dir: String,
+    #[serde(default = "default_locale")] // 请求的语言没有对应模板时使用的语言
+    pub default_locale: String,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        TemplateConfig {
+            dir: default_templates_dir(),
+            default_locale: default_locale(),
+        }
+    }
+}
+
+// 默认模板目录
+fn default_templates_dir() -> String {
+    "templates".to_string()
+}
+
+// 默认语言
+fn default_locale() -> String {
+    "en".to_string()
+}
+
+// 邮件模板，text 和 html 至少需要一个
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EmailTemplate {
+    #[serde(default)] // 通过 PUT /templates/{name} 保存时可省略
+    pub name: String,
+    pub subject: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] // 纯文本正文模板
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] // HTML 正文模板，变量会被转义
+    pub html: Option<String>,
+}
+
+// 渲染后的主题和正文
+pub struct RenderedTemplate {
+    pub subject: String,
+    pub text: Option<String>,
+    pub html: Option<String>,
+}
+
+// 模板存储，模板保存在内存中并同步写入模板目录
+pub struct TemplateStore {
+    dir: PathBuf,
+    default_locale: String,
+    templates: RwLock<HashMap<String, EmailTemplate>>,
+    text_engine: Handlebars<'static>,
+    html_engine: Handlebars<'static>,
+}
+
+impl TemplateStore {
+    // 打开模板目录并加载所有模板
+    pub fn open(config: &TemplateConfig) -> io::Result<Self> {
+        let dir = PathBuf::from(&config.dir);
+        std::fs::create_dir_all(&dir)?;
+
+        let mut templates = HashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(stem) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            // 旧版本按原样保存的名称改为规范的大小写
+            let name = normalize_name(stem);
+            let path = if name == stem {
+                path
+            } else {
+                let target = dir.join(format!("{}.json", name));
+                if target.exists() {
+                    warn!(
+                        "Skipping template {}, {} already exists",
+                        path.display(),
+                        target.display()
+                    );
+                    continue;
+                }
+                info!("Renaming template {} to {}", stem, name);
+                std::fs::rename(&path, &target)?;
+                target
+            };
+            let template = std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| {
+                    serde_json::from_slice::<EmailTemplate>(&data).map_err(|e| e.to_string())
+                })
+                .and_then(|template| {
+                    check_template(EmailTemplate {
+                        name: name.clone(),
+                        ..template
+                    })
+                    .map_err(|e| {
+                        e.field_errors()
+                            .iter()
+                            .map(|e| format!("{}: {}", e.field, e.reason))
+                            .collect::<Vec<_>>()
+                            .join("; ")
+                    })
+                });
+            match template {
+                Ok(template) => {
+                    templates.insert(name, template);
+                }
+                Err(e) => warn!("Skipping invalid template {}: {}", path.display(), e),
+            }
+        }
+        info!(
+            "Loaded {} template(s) from {}",
+            templates.len(),
+            dir.display()
+        );
+
+        // 主题和纯文本不做转义，HTML 中的变量默认转义
+        let mut text_engine = Handlebars::new();
+        text_engine.set_strict_mode(true);
+        text_engine.register_escape_fn(handlebars::no_escape);
+        let mut html_engine = Handlebars::new();
+        html_engine.set_strict_mode(true);
+
+        Ok(TemplateStore {
+            dir,
+            default_locale: config.default_locale.clone(),
+            templates: RwLock::new(templates),
+            text_engine,
+            html_engine,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<EmailTemplate> {
+        self.templates
+            .read()
+            .unwrap()
+            .get(&normalize_name(name))
+            .cloned()
+    }
+
+    // 按名称排序的模板列表
+    pub fn list(&self) -> Vec<EmailTemplate> {
+        let mut templates = self
+            .templates
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        templates
+    }
+
+    // 保存模板，返回保存后的模板（名称中的语言已规范化）和是否为新建
+    pub async fn save(
+        &self,
+        template: EmailTemplate,
+        replace: bool,
+    ) -> Result<(EmailTemplate, bool), EmailError> {
+        let mut template = check_template(template)?;
+        template.name = normalize_name(&template.name);
+        let exists = self.templates.read().unwrap().contains_key(&template.name);
+        if exists && !replace {
+            warn!("Template {} already exists", template.name);
+            return Err(EmailError::TemplateExists(template.name));
+        }
+
+        let data = serde_json::to_vec_pretty(&template)
+            .map_err(|e| EmailError::TemplateStorage(e.into()))?;
+        write_atomic(&self.path(&template.name), &data)
+            .await
+            .map_err(|e| {
+                warn!("Failed to save template {}: {}", template.name, e);
+                EmailError::TemplateStorage(e)
+            })?;
+        info!("Template {} saved", template.name);
+
+        self.templates
+            .write()
+            .unwrap()
+            .insert(template.name.clone(), template.clone());
+        Ok((template, !exists))
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), EmailError> {
+        let name = &normalize_name(name);
+        if self.templates.write().unwrap().remove(name).is_none() {
+            return Err(EmailError::TemplateNotFound(name.to_string()));
+        }
+        match tokio::fs::remove_file(self.path(name)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to delete template {}: {}", name, e);
+                return Err(EmailError::TemplateStorage(e));
+            }
+            _ => {}
+        }
+        info!("Template {} deleted", name);
+        Ok(())
+    }
+
+    // 按语言查找模板，例如 zh-Hant-TW 依次查找 <名称>.zh-Hant-TW、<名称>.zh-Hant、<名称>.zh，
+    // 然后按同样的方式查找默认语言，最后使用不带语言的 <名称>，语言不区分大小写
+    fn resolve(&self, name: &str, locale: Option<&str>) -> Option<EmailTemplate> {
+        let templates = self.templates.read().unwrap();
+        let locales = locale
+            .filter(|locale| !locale.is_empty())
+            .into_iter()
+            .chain([self.default_locale.as_str()]);
+        for locale in locales {
+            let mut locale = normalize_locale(locale);
+            loop {
+                if let Some(template) = templates.get(&format!("{}.{}", name, locale)) {
+                    return Some(template.clone());
+                }
+                match locale.rfind('-') {
+                    Some(pos) => locale.truncate(pos),
+                    None => break,
+                }
+            }
+        }
+        templates.get(name).cloned()
+    }
+
+    // 使用变量渲染指定模板，locale 为空时使用默认语言
+    pub fn render(
+        &self,
+        name: &str,
+        locale: Option<&str>,
+        variables: &Map<String, Value>,
+    ) -> Result<RenderedTemplate, EmailError> {
+        let template = self.resolve(name, locale).ok_or_else(|| {
+            warn!("Template {} not found for locale {:?}", name, locale);
+            EmailError::TemplateNotFound(name.to_string())
+        })?;
+        debug!(
+            "Rendering template {} for locale {:?}",
+            template.name, locale
+        );
+
+        let render = |source: &Option<String>, html| {
+            source
+                .as_deref()
+                .map(|source| self.render_str(source, variables, html))
+                .transpose()
+        };
+        Ok(RenderedTemplate {
+            subject: self.render_str(&template.subject, variables, false)?,
+            text: render(&template.text, false)?,
+            html: render(&template.html, true)?,
+        })
+    }
+
+    // 渲染单个模板字符串，html 为 true 时转义变量中的 HTML 字符
+    pub fn render_str(
+        &self,
+        source: &str,
+        variables: &Map<String, Value>,
+        html: bool,
+    ) -> Result<String, EmailError> {
+        let engine = if html {
+            &self.html_engine
+        } else {
+            &self.text_engine
+        };
+        engine.render_template(source, variables).map_err(|e| {
+            debug!("Failed to render template: {}", e);
+            match e.reason() {
+                RenderErrorReason::MissingVariable(Some(name)) => EmailError::TemplateRender {
+                    field: format!("variables.{}", name),
+                    reason: "missing variable".to_string(),
+                },
+                reason => EmailError::TemplateRender {
+                    field: "template".to_string(),
+                    reason: reason.to_string(),
+                },
+            }
+        })
+    }
+
+    // 根据请求中的 template 和 variables 渲染主题和正文，请求中已有的字段优先
+    pub fn apply(&self, mut req: EmailRequest) -> Result<EmailRequest, EmailError> {
+        let Some(name) = req.template.as_deref().filter(|name| !name.is_empty()) else {
+            return Ok(req);
+        };
+        let rendered = self.render(name, req.locale.as_deref(), &req.variables)?;
+
+        if req.subject.as_deref().is_none_or(str::is_empty) {
+            req.subject = Some(rendered.subject);
+        }
+        // markdown_body 同时提供纯文本和 HTML 正文
+        let has_markdown = req.markdown_body.as_deref().is_some_and(|m| !m.is_empty());
+        if req.text_body.as_deref().is_none_or(str::is_empty)
+            && req.body.is_empty()
+            && !has_markdown
+        {
+            req.text_body = rendered.text;
+        }
+        if req.html_body.as_deref().is_none_or(str::is_empty) && !has_markdown {
+            req.html_body = rendered.html;
+        }
+        Ok(req)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+}
+
+// 模板名称中第一个 '.' 之后是语言，按 BCP 47 的习惯统一大小写
+fn normalize_name(name: &str) -> String {
+    match name.split_once('.') {
+        Some((name, locale)) => format!("{}.{}", name, normalize_locale(locale)),
+        None => name.to_string(),
+    }
+}
+
+// 语言小写，文字首字母大写，地区大写，例如 zh_hant_tw 转为 zh-Hant-TW
+fn normalize_locale(locale: &str) -> String {
+    locale
+        .split(['-', '_'])
+        .enumerate()
+        .map(|(index, subtag)| match subtag.len() {
+            4 if index > 0 && subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                subtag[..1].to_ascii_uppercase() + &subtag[1..].to_ascii_lowercase()
+            }
+            2 if index > 0 => subtag.to_ascii_uppercase(),
+            _ => subtag.to_ascii_lowercase(),
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// 校验模板名称和语法
+fn check_template(template: EmailTemplate) -> Result<EmailTemplate, EmailError> {
+    let name = &template.name;
+    let mut errors = Vec::new();
+    if name.is_empty()
+        || name.len() > MAX_NAME_LENGTH
+        || name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+    {
+        errors.push(EmailError::InvalidField {
+            field: "name".to_string(),
+            reason: format!(
+                "must be 1 to {} letters, digits, '.', '-' or '_' and not start with '.'",
+                MAX_NAME_LENGTH
+            ),
+        });
+    }
+    if template.text.is_none() && template.html.is_none() {
+        errors.push(EmailError::InvalidField {
+            field: "text".to_string(),
+            reason: "one of text or html is required".to_string(),
+        });
+    }
+    for (field, source) in [
+        ("subject", Some(&template.subject)),
+        ("text", template.text.as_ref()),
+        ("html", template.html.as_ref()),
+    ] {
+        if let Some(Err(e)) = source.map(|source| Template::compile(source)) {
+            errors.push(EmailError::InvalidField {
+                field: field.to_string(),
+                reason: match e.pos() {
+                    Some((line, column)) => {
+                        format!("line {}, column {}: {}", line, column, e.reason())
+                    }
+                    None => e.reason().to_string(),
+                },
+            });
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(EmailError::from_field_errors(errors));
+    }
+    Ok(template)
+}
+
+// 模板列表
+pub async fn list_templates(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+) -> Result<Json<Vec<EmailTemplate>>, EmailError> {
+    client.require(Scope::Templates)?;
+
+    Ok(Json(state.templates.list()))
+}
+
+// 查询单个模板
+pub async fn get_template(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+) -> Result<Json<EmailTemplate>, EmailError> {
+    client.require(Scope::Templates)?;
+
+    state.templates.get(&name).map(Json).ok_or_else(|| {
+        debug!("Template {} not found", name);
+        EmailError::TemplateNotFound(name)
+    })
+}
+
+// 新建模板，同名模板已存在时返回 409
+pub async fn create_template(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Json(template): Json<EmailTemplate>,
+) -> Result<impl IntoResponse, EmailError> {
+    client.require(Scope::Templates)?;
+
+    let (template, _) = state.templates.save(template, false).await?;
+    Ok((StatusCode::CREATED, Json(template)))
+}
+
+// 新建或替换模板
+pub async fn put_template(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+    Json(mut template): Json<EmailTemplate>,
+) -> Result<impl IntoResponse, EmailError> {
+    client.require(Scope::Templates)?;
+
+    if !template.name.is_empty() && normalize_name(&template.name) != normalize_name(&name) {
+        return Err(EmailError::InvalidField {
+            field: "name".to_string(),
+            reason: "does not match the template name in the path".to_string(),
+        });
+    }
+    template.name = name;
+    let (template, created) = state.templates.save(template, true).await?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((status, Json(template)))
+}
+
+// 删除模板
+pub async fn delete_template(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+) -> Result<Json<ApiResponse>, EmailError> {
+    client.require(Scope::Templates)?;
+
+    state.templates.delete(&name).await?;
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Template {} deleted", name),
+        ..Default::default()
+    }))
+}
+
+// 渲染模板并返回完整邮件，不发送
+// 请求体与 /send-email 相同，可以指定收件人等字段
+pub async fn render_template(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Path(name): Path<String>,
+    Json(mut req): Json<EmailRequest>,
+) -> Result<Json<MessagePreview>, EmailError> {
+    client.require(Scope::Templates)?;
+
+    if !req.attachments.is_empty() {
+        return Err(EmailError::InvalidField {
+            field: "attachments".to_string(),
+            reason: "not supported when rendering a template".to_string(),
+        });
+    }
+    req.template = Some(name);
+    preview_email(&state, &client, req, Vec::new()).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(name: &str) -> EmailTemplate {
+        EmailTemplate {
+            name: name.to_string(),
+            subject: name.to_string(),
+            text: Some("hello".to_string()),
+            html: None,
+        }
+    }
+
+    #[test]
+    fn locales_are_normalized() {
+        assert_eq!(normalize_locale("zh-cn"), "zh-CN");
+        assert_eq!(normalize_locale("ZH_hant_tw"), "zh-Hant-TW");
+        assert_eq!(normalize_locale("EN"), "en");
+        assert_eq!(normalize_locale("es-419"), "es-419");
+        assert_eq!(normalize_name("welcome.zh-cn"), "welcome.zh-CN");
+        assert_eq!(normalize_name("Welcome"), "Welcome");
+    }
+
+    #[tokio::test]
+    async fn locale_lookup_ignores_case() {
+        let dir = std::env::temp_dir().join(format!("templates-{}", uuid::Uuid::new_v4()));
+        let store = TemplateStore::open(&TemplateConfig {
+            dir: dir.to_string_lossy().into_owned(),
+            default_locale: "EN".to_string(),
+        })
+        .unwrap();
+        for name in ["welcome.en", "welcome.zh-cn", "welcome.zh-hant"] {
+            store.save(template(name), false).await.unwrap();
+        }
+
+        let resolve = |locale| store.resolve("welcome", Some(locale)).unwrap().name;
+        assert_eq!(resolve("zh-CN"), "welcome.zh-CN");
+        assert_eq!(resolve("ZH-cn"), "welcome.zh-CN");
+        assert_eq!(resolve("zh-HANT-tw"), "welcome.zh-Hant");
+        assert_eq!(resolve("fr"), "welcome.en");
+
+        // 大小写不同的名称指向同一个模板
+        assert!(store.save(template("welcome.ZH-CN"), false).await.is_err());
+        assert!(store.get("welcome.ZH-cn").is_some());
+        assert!(dir.join("welcome.zh-CN.json").exists());
+        store.delete("welcome.zh-cn").await.unwrap();
+        assert!(!dir.join("welcome.zh-CN.json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use base64::prelude::*;
+use lettre::transport::smtp::client::{
+    Certificate, CertificateStore, Identity, Tls, TlsParameters, TlsVersion,
+};
+use serde::Deserialize;
+use tracing::debug;
+
+// TLS 配置
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TlsSettings {
+    #[serde(default)] // 连接方式，未配置时按端口选择
+    pub mode: Option<TlsMode>,
+    #[serde(default)] // 额外信任的 CA 证书（PEM，可包含多个证书）
+    pub ca_file: Option<String>,
+    #[serde(default)] // 固定的信任锚（PEM），配置后不再信任系统 CA，不能与 ca_file 同时使用
+    pub pinned_certificate: Option<String>,
+    #[serde(default)] // 最低 TLS 版本
+    pub min_version: Option<TlsMinVersion>,
+    #[serde(default)] // 客户端证书（PEM），用于双向认证
+    pub client_cert: Option<String>,
+    #[serde(default)] // 客户端证书的私钥（PEM）
+    pub client_key: Option<String>,
+    #[serde(default)] // SNI 和证书校验使用的主机名，未配置时使用 smtp_server
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    None,     // 明文连接
+    Starttls, // 服务器支持时使用 STARTTLS，否则明文
+    Required, // 必须使用 STARTTLS
+    Implicit, // 连接建立后直接进行 TLS 握手（SMTPS）
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum TlsMinVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsSettings {
+    // 根据配置创建连接方式，证书文件无效时返回错误
+    pub fn build(&self, smtp_server: &str, smtp_port: u16) -> Result<Tls, String> {
+        // ca_file 中的 CA 会与固定证书一起作为信任锚，使固定失去意义
+        if self.pinned_certificate.is_some() && self.ca_file.is_some() {
+            return Err("pinned_certificate and ca_file cannot be set together".to_string());
+        }
+
+        // 兼容旧配置：465 使用 SMTPS，587 要求 STARTTLS，其他端口尽量使用 STARTTLS
+        let mode = self.mode.unwrap_or(match smtp_port {
+            465 => TlsMode::Implicit,
+            587 => TlsMode::Required,
+            _ => TlsMode::Starttls,
+        });
+        if mode == TlsMode::None {
+            return Ok(Tls::None);
+        }
+
+        let server_name = self.server_name.as_deref().unwrap_or(smtp_server);
+        let mut builder = TlsParameters::builder(server_name.to_string());
+        // 固定证书作为唯一的信任锚，证书链和主机名仍会校验，
+        // 因此服务器证书必须是该证书本身（自签名）或由它签发
+        if let Some(path) = &self.pinned_certificate {
+            builder = builder
+                .certificate_store(CertificateStore::None)
+                .add_root_certificate(read_certificate(path)?);
+        }
+        if let Some(path) = &self.ca_file {
+            builder = builder.add_root_certificate(read_certificate(path)?);
+        }
+        if let Some(version) = self.min_version {
+            builder = builder.set_min_tls_version(match version {
+                TlsMinVersion::Tls12 => TlsVersion::Tlsv12,
+                TlsMinVersion::Tls13 => TlsVersion::Tlsv13,
+            });
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pem(&certificate_der(cert)?, &read_file(key)?)
+                    .map_err(|e| format!("invalid client certificate {}: {}", cert, e))?;
+                builder = builder.identify_with(identity);
+            }
+            (None, None) => {}
+            _ => return Err("client_cert and client_key must be set together".to_string()),
+        }
+        let parameters = builder
+            .build()
+            .map_err(|e| format!("invalid TLS configuration: {}", e))?;
+
+        debug!(
+            "Using TLS mode {:?} for {}:{} (server name {})",
+            mode, smtp_server, smtp_port, server_name
+        );
+        Ok(match mode {
+            TlsMode::None => Tls::None,
+            TlsMode::Starttls => Tls::Opportunistic(parameters),
+            TlsMode::Required => Tls::Required(parameters),
+            TlsMode::Implicit => Tls::Wrapper(parameters),
+        })
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))
+}
+
+// lettre 在 rustls 下直接把传入的内容当作 DER 使用，需要先从 PEM 中取出第一个证书
+fn certificate_der(path: &str) -> Result<Vec<u8>, String> {
+    let pem = String::from_utf8(read_file(path)?)
+        .map_err(|_| format!("invalid certificate {}: not a PEM file", path))?;
+    let body = pem
+        .split("-----BEGIN CERTIFICATE-----")
+        .nth(1)
+        .and_then(|rest| rest.split("-----END CERTIFICATE-----").next())
+        .ok_or_else(|| format!("invalid certificate {}: no certificate found", path))?;
+    BASE64_STANDARD
+        .decode(body.split_whitespace().collect::<String>())
+        .map_err(|e| format!("invalid certificate {}: {}", path, e))
+}
+
+fn read_certificate(path: &str) -> Result<Certificate, String> {
+    Certificate::from_pem(&read_file(path)?)
+        .map_err(|e| format!("invalid certificate {}: {}", path, e))
+}
@@ -0,0 +1,232 @@
+use crate::{
+    auth::{ApiClient, Scope},
+    queue::write_atomic,
+    relay::{Delivery, DeliveryError, RelayPool},
+    ApiResponse, AppState, EmailConfig, EmailError,
+};
+use axum::{
+    extract::{Json, Query, State},
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use lettre::{address::Envelope, AsyncSendmailTransport, AsyncTransport, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+// 桩传输最多保留的邮件数，超出后丢弃最早的邮件
+const STUB_CAPACITY: usize = 1000;
+
+// 投递方式
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Smtp, // 通过 SMTP 中继发送
+    Sendmail, // 调用本地 sendmail 程序
+    File,     // 写入目录中的 .eml 文件，不发送
+    Stub,     // 保存在内存中，不发送，可通过 /stub/messages 查询
+}
+
+// 账户的投递传输
+pub enum Transport {
+    Smtp(Box<RelayPool>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    File(PathBuf),
+    Stub(StubTransport),
+}
+
+impl Transport {
+    pub fn new(name: &str, config: &EmailConfig) -> Result<Self, String> {
+        let transport = match config.transport {
+            TransportKind::Smtp => {
+                if config.smtp_server.is_empty() && config.relays.is_empty() {
+                    return Err("neither smtp_server nor relays is set".to_string());
+                }
+                info!(
+                    "Configuring SMTP transport for account {} with {} relay(s)",
+                    name,
+                    config.relays.len().max(1)
+                );
+                Transport::Smtp(Box::new(RelayPool::new(config)?))
+            }
+            TransportKind::Sendmail => {
+                info!(
+                    "Configuring sendmail transport for account {} using {}",
+                    name, config.sendmail_command
+                );
+                Transport::Sendmail(AsyncSendmailTransport::new_with_command(
+                    &config.sendmail_command,
+                ))
+            }
+            TransportKind::File => {
+                info!(
+                    "Configuring file transport for account {}, messages are written to {}",
+                    name, config.file_dir
+                );
+                std::fs::create_dir_all(&config.file_dir)
+                    .map_err(|e| format!("failed to create {}: {}", config.file_dir, e))?;
+                Transport::File(PathBuf::from(&config.file_dir))
+            }
+            TransportKind::Stub => {
+                info!(
+                    "Configuring stub transport for account {}, messages are kept in memory",
+                    name
+                );
+                Transport::Stub(StubTransport {
+                    account: name.to_string(),
+                    messages: Mutex::new(VecDeque::new()),
+                })
+            }
+        };
+        Ok(transport)
+    }
+
+    // 投递邮件，id 为队列中的消息 ID
+    pub async fn send(
+        &self,
+        id: &str,
+        envelope: &Envelope,
+        raw: &[u8],
+    ) -> Result<Delivery, DeliveryError> {
+        match self {
+            Transport::Smtp(relays) => relays.send(envelope, raw).await,
+            Transport::Sendmail(sendmail) => match sendmail.send_raw(envelope, raw).await {
+                Ok(()) => Ok(Delivery {
+                    relay: "sendmail".to_string(),
+                    response: "Accepted by sendmail".to_string(),
+                }),
+                // sendmail 失败多为本地问题，稍后重试
+                Err(e) => Err(DeliveryError::Transient {
+                    relay: "sendmail".to_string(),
+                    error: e.to_string(),
+                }),
+            },
+            Transport::File(dir) => {
+                let path = dir.join(format!("{}.eml", id));
+                match write_atomic(&path, raw).await {
+                    Ok(()) => Ok(Delivery {
+                        relay: "file".to_string(),
+                        response: format!("Written to {}", path.display()),
+                    }),
+                    Err(e) => Err(DeliveryError::Transient {
+                        relay: "file".to_string(),
+                        error: format!("Failed to write {}: {}", path.display(), e),
+                    }),
+                }
+            }
+            Transport::Stub(stub) => {
+                stub.capture(id, envelope, raw);
+                Ok(Delivery {
+                    relay: "stub".to_string(),
+                    response: "Captured by stub transport".to_string(),
+                })
+            }
+        }
+    }
+}
+
+// 桩传输保存的邮件
+#[derive(Serialize, Clone)]
+pub struct StubMessage {
+    pub id: String,
+    pub account: String,
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub message: String, // 完整的 MIME 邮件
+    pub captured_at: DateTime<Utc>,
+}
+
+// 内存中的桩传输，供测试环境检查生成的邮件
+pub struct StubTransport {
+    account: String,
+    messages: Mutex<VecDeque<StubMessage>>,
+}
+
+impl StubTransport {
+    fn capture(&self, id: &str, envelope: &Envelope, raw: &[u8]) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= STUB_CAPACITY {
+            messages.pop_front();
+        }
+        messages.push_back(StubMessage {
+            id: id.to_string(),
+            account: self.account.clone(),
+            from: envelope.from().map(ToString::to_string),
+            to: envelope.to().iter().map(ToString::to_string).collect(),
+            message: String::from_utf8_lossy(raw).into_owned(),
+            captured_at: Utc::now(),
+        });
+    }
+}
+
+// 桩邮件查询条件
+#[derive(Deserialize)]
+pub struct StubFilter {
+    #[serde(default)]
+    account: Option<String>,
+}
+
+fn stub_transports<'a>(
+    state: &'a AppState,
+    filter: &'a StubFilter,
+) -> impl Iterator<Item = &'a StubTransport> {
+    state
+        .accounts
+        .values()
+        .filter_map(move |account| match &account.transport {
+            Transport::Stub(stub)
+                if filter
+                    .account
+                    .as_ref()
+                    .is_none_or(|name| *name == stub.account) =>
+            {
+                Some(stub)
+            }
+            _ => None,
+        })
+}
+
+// 查询桩传输保存的邮件，按保存时间排序
+// 邮件不记录发送的客户端，包含所有客户端的邮件，因此只允许 admin 查询
+pub async fn list_stub_messages(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Query(filter): Query<StubFilter>,
+) -> Result<Json<Vec<StubMessage>>, EmailError> {
+    client.require(Scope::Admin)?;
+
+    let mut messages = stub_transports(&state, &filter)
+        .flat_map(|stub| stub.messages.lock().unwrap().clone())
+        .collect::<Vec<_>>();
+    messages.sort_by_key(|message| message.captured_at);
+    Ok(Json(messages))
+}
+
+// 清空桩传输保存的邮件
+pub async fn clear_stub_messages(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<Arc<ApiClient>>,
+    Query(filter): Query<StubFilter>,
+) -> Result<Json<ApiResponse>, EmailError> {
+    client.require(Scope::Admin)?;
+
+    let cleared = stub_transports(&state, &filter)
+        .map(|stub| {
+            let mut messages = stub.messages.lock().unwrap();
+            let count = messages.len();
+            messages.clear();
+            count
+        })
+        .sum::<usize>();
+    info!("Cleared {} stub message(s)", cleared);
+    Ok(Json(ApiResponse {
+        status: "success".to_string(),
+        message: format!("Cleared {} message(s)", cleared),
+        ..Default::default()
+    }))
+}